@@ -0,0 +1,394 @@
+//! Training checkpoints.
+//!
+//! A checkpoint holds everything needed to resume training exactly where it
+//! stopped: the current model (in the [`model`] format), the optimizer state,
+//! the position within training and the seed of the run. Integers and floats
+//! are big-endian, and the file ends with a CRC-32 of its contents.
+//!
+//! Checkpoints are kept in a directory as files named after the number of
+//! completed epochs and batches, alongside a `manifest.ron` listing them
+//! oldest first and naming the best one by validation cost.
+
+use crate::config::Retention;
+use crate::model::{self, Reader};
+use crate::network::{NetConf, Vector};
+use crate::optim;
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+const MAGIC: [u8; 4] = *b"DGCK";
+const VERSION: u32 = 1;
+const MANIFEST: &str = "manifest.ron";
+
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub conf: NetConf,
+    pub optimizer: optim::State,
+    pub config_hash: u32,
+    pub seed: u64,
+    pub progress: Progress,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Progress {
+    /// Epoch to resume in.
+    pub epoch: usize,
+    /// Number of batches of `epoch` already completed.
+    pub batch: usize,
+    /// Running average training cost for the batches of `epoch` completed so far.
+    pub epoch_cost: f64,
+    pub best_cost: Option<f64>,
+    pub stale_epochs: usize,
+    /// Number of times training has been rolled back after diverging.
+    pub rollbacks: usize,
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        if !self.conf.is_finite() {
+            anyhow::bail!("refusing to save a checkpoint with non-finite parameters");
+        }
+        model::write_atomic(path.as_ref(), &self.encode())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        std::fs::read(path)
+            .map_err(Into::into)
+            .and_then(|bytes| Self::decode(&bytes))
+            .with_context(|| format!("failed to load checkpoint `{}`", path.display()))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.config_hash.to_be_bytes());
+        bytes.extend_from_slice(&self.seed.to_be_bytes());
+        let progress = &self.progress;
+        bytes.extend_from_slice(&(progress.epoch as u64).to_be_bytes());
+        bytes.extend_from_slice(&(progress.batch as u64).to_be_bytes());
+        bytes.extend_from_slice(&progress.epoch_cost.to_be_bytes());
+        bytes.extend_from_slice(&progress.best_cost.unwrap_or(f64::NAN).to_be_bytes());
+        bytes.extend_from_slice(&(progress.stale_epochs as u64).to_be_bytes());
+        bytes.extend_from_slice(&(progress.rollbacks as u64).to_be_bytes());
+
+        bytes.extend_from_slice(&self.optimizer.steps.to_be_bytes());
+        bytes.extend_from_slice(&(self.optimizer.buffers.len() as u32).to_be_bytes());
+        for buffer in &self.optimizer.buffers {
+            bytes.extend_from_slice(&(buffer.nrows() as u64).to_be_bytes());
+            buffer
+                .iter()
+                .for_each(|n| bytes.extend_from_slice(&n.to_be_bytes()));
+        }
+
+        let model = model::encode(&self.conf);
+        bytes.extend_from_slice(&(model.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&model);
+
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+            anyhow::bail!("not a checkpoint file (bad magic number)");
+        }
+        if bytes.len() < MAGIC.len() + 8 {
+            anyhow::bail!("checkpoint file is truncated");
+        }
+
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        let mut r = Reader(&body[MAGIC.len()..]);
+
+        let version = r.u32()?;
+        if version != VERSION {
+            anyhow::bail!("unsupported checkpoint version: {version} (expected {VERSION})");
+        }
+
+        let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+        if checksum != crc32fast::hash(body) {
+            anyhow::bail!("checkpoint checksum mismatch (file is truncated or corrupted)");
+        }
+
+        let config_hash = r.u32()?;
+        let seed = r.u64()?;
+        let progress = Progress {
+            epoch: r.u64()? as usize,
+            batch: r.u64()? as usize,
+            epoch_cost: r.f64()?,
+            best_cost: Some(r.f64()?).filter(|n| !n.is_nan()),
+            stale_epochs: r.u64()? as usize,
+            rollbacks: r.u64()? as usize,
+        };
+
+        let steps = r.u64()?;
+        let buffers = (0..r.u32()?)
+            .map(|_| {
+                let len = r.u64()? as usize;
+                let data = r.take(len * 8)?;
+                Ok(Vector::from_iterator(
+                    len,
+                    data.chunks(8)
+                        .map(|chunk| f64::from_be_bytes(chunk.try_into().unwrap())),
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let len = r.u64()? as usize;
+        let conf = model::decode(r.take(len)?)?;
+        if !r.0.is_empty() {
+            anyhow::bail!("checkpoint has {} trailing bytes", r.0.len());
+        }
+
+        Ok(Self {
+            conf,
+            optimizer: optim::State { steps, buffers },
+            config_hash,
+            seed,
+            progress,
+        })
+    }
+}
+
+/// A directory of checkpoints, pruned according to a [`Retention`] policy.
+pub struct Store {
+    dir: PathBuf,
+    retention: Retention,
+    manifest: Manifest,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Default)]
+struct Manifest {
+    /// File names of the checkpoints kept, oldest first.
+    checkpoints: Vec<String>,
+    /// File name of the checkpoint with the best validation cost, if kept.
+    best: Option<String>,
+}
+
+impl Store {
+    /// Opens the store in `dir`, creating the directory if needed.
+    pub fn open(dir: impl Into<PathBuf>, retention: Retention) -> anyhow::Result<Self> {
+        if retention.keep_last == 0 {
+            anyhow::bail!("at least one checkpoint must be kept");
+        }
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).with_context(|| {
+            format!("failed to create checkpoint directory `{}`", dir.display())
+        })?;
+        let path = dir.join(MANIFEST);
+        let manifest = if path.exists() {
+            std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|s| ron::from_str(&s).map_err(Into::into))
+                .with_context(|| format!("failed to read manifest `{}`", path.display()))?
+        } else {
+            Manifest::default()
+        };
+        Ok(Self {
+            dir,
+            retention,
+            manifest,
+        })
+    }
+
+    /// The most recent checkpoint.
+    pub fn latest(&self) -> anyhow::Result<Checkpoint> {
+        let name = self
+            .manifest
+            .checkpoints
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("no checkpoints in `{}`", self.dir.display()))?;
+        Checkpoint::load(self.dir.join(name))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.manifest.checkpoints.is_empty()
+    }
+
+    /// Deletes the checkpoints of a previous run, so that a new run doesn't
+    /// mix its checkpoints with them. Returns the number deleted.
+    pub fn clear(&mut self) -> anyhow::Result<usize> {
+        let removed = std::mem::take(&mut self.manifest).checkpoints;
+        self.write_manifest()?;
+        self.remove(&removed)?;
+        Ok(removed.len())
+    }
+
+    /// Saves `checkpoint`, as the best so far if `best`, then deletes the
+    /// checkpoints that are no longer kept.
+    pub fn save(&mut self, checkpoint: &Checkpoint, best: bool) -> anyhow::Result<()> {
+        let Progress { epoch, batch, .. } = checkpoint.progress;
+        let name = format!("epoch-{epoch:04}-batch-{batch:05}");
+        checkpoint.save(self.dir.join(&name))?;
+
+        let manifest = &mut self.manifest;
+        manifest.checkpoints.retain(|n| *n != name);
+        manifest.checkpoints.push(name.clone());
+        if best {
+            manifest.best = Some(name);
+        } else if manifest.best.as_ref() == Some(&name) {
+            // The best checkpoint has just been overwritten.
+            manifest.best = None;
+        }
+        let old = manifest
+            .checkpoints
+            .len()
+            .saturating_sub(self.retention.keep_last);
+        let (best, removed): (Vec<_>, Vec<_>) = manifest
+            .checkpoints
+            .drain(..old)
+            .partition(|n| self.retention.keep_best && manifest.best.as_ref() == Some(n));
+        manifest.checkpoints.splice(0..0, best);
+        if manifest.best.as_ref().is_some_and(|n| removed.contains(n)) {
+            manifest.best = None;
+        }
+
+        // The manifest is updated first so it never lists a missing file.
+        self.write_manifest()?;
+        self.remove(&removed)
+    }
+
+    fn write_manifest(&self) -> anyhow::Result<()> {
+        let text = ron::ser::to_string_pretty(&self.manifest, Default::default())?;
+        model::write_atomic(&self.dir.join(MANIFEST), text.as_bytes())
+    }
+
+    fn remove(&self, names: &[String]) -> anyhow::Result<()> {
+        for name in names {
+            let path = self.dir.join(name);
+            match std::fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(err).with_context(|| {
+                        format!("failed to delete checkpoint `{}`", path.display())
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Init;
+    use crate::network::{Activation, LayerKind, LayerSpec, Shape};
+    use rand::SeedableRng;
+
+    /// An empty directory for a test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("digits-nn-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn checkpoint(epoch: usize, batch: usize) -> Checkpoint {
+        let spec = LayerSpec {
+            kind: LayerKind::Dense { size: 2 },
+            activation: Activation::IDENTITY,
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(epoch as u64);
+        Checkpoint {
+            conf: NetConf::new(Shape::flat(3), &[(spec, Init::HeNormal)], &mut rng).unwrap(),
+            optimizer: optim::State::default(),
+            config_hash: 0,
+            seed: 0,
+            progress: Progress {
+                epoch,
+                batch,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn name(epoch: usize) -> String {
+        format!("epoch-{epoch:04}-batch-00000")
+    }
+
+    /// The checkpoint files in `dir`, sorted.
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name != MANIFEST)
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn keeps_the_latest_and_the_best() {
+        let dir = TempDir::new("retention");
+        let retention = Retention {
+            keep_last: 2,
+            keep_best: true,
+        };
+        let mut store = Store::open(&dir.0, retention).unwrap();
+        for (epoch, best) in [(1, true), (2, true), (3, false), (4, false), (5, false)] {
+            store.save(&checkpoint(epoch, 0), best).unwrap();
+        }
+        assert_eq!(files(&dir.0), [name(2), name(4), name(5)]);
+        assert_eq!(store.manifest.checkpoints, [name(2), name(4), name(5)]);
+        assert_eq!(store.manifest.best, Some(name(2)));
+
+        // The manifest is read back when the store is reopened.
+        let store = Store::open(&dir.0, retention).unwrap();
+        assert_eq!(store.manifest.checkpoints, [name(2), name(4), name(5)]);
+        assert_eq!(store.manifest.best, Some(name(2)));
+        assert_eq!(store.latest().unwrap().progress.epoch, 5);
+    }
+
+    #[test]
+    fn prunes_the_best_unless_kept() {
+        let dir = TempDir::new("no-best");
+        let retention = Retention {
+            keep_last: 1,
+            keep_best: false,
+        };
+        let mut store = Store::open(&dir.0, retention).unwrap();
+        store.save(&checkpoint(1, 0), true).unwrap();
+        store.save(&checkpoint(2, 0), false).unwrap();
+        assert_eq!(files(&dir.0), [name(2)]);
+        assert_eq!(store.manifest.best, None);
+    }
+
+    #[test]
+    fn forgets_an_overwritten_best() {
+        let dir = TempDir::new("overwrite");
+        let mut store = Store::open(&dir.0, Retention::default()).unwrap();
+        store.save(&checkpoint(1, 0), true).unwrap();
+        store.save(&checkpoint(1, 0), false).unwrap();
+        assert_eq!(store.manifest.checkpoints, [name(1)]);
+        assert_eq!(store.manifest.best, None);
+    }
+
+    #[test]
+    fn clear_deletes_previous_runs() {
+        let dir = TempDir::new("clear");
+        let mut store = Store::open(&dir.0, Retention::default()).unwrap();
+        store.save(&checkpoint(1, 0), true).unwrap();
+        store.save(&checkpoint(2, 0), false).unwrap();
+        std::fs::write(dir.0.join("unrelated"), b"").unwrap();
+
+        let mut store = Store::open(&dir.0, Retention::default()).unwrap();
+        assert_eq!(store.clear().unwrap(), 2);
+        assert_eq!(files(&dir.0), ["unrelated"]);
+        assert!(store.latest().is_err());
+        let store = Store::open(&dir.0, Retention::default()).unwrap();
+        assert!(store.manifest.checkpoints.is_empty());
+        assert_eq!(store.manifest.best, None);
+    }
+}
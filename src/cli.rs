@@ -0,0 +1,54 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// A neural network for identifying handwritten digits from the MNIST dataset.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Configuration file
+    #[arg(long, global = true, default_value = "config.ron")]
+    pub config: PathBuf,
+    /// Model file to load and save
+    #[arg(long, global = true, default_value = "network")]
+    pub model: PathBuf,
+    /// Checkpoint directory to write to during training and resume from
+    #[arg(long, global = true, default_value = "checkpoints")]
+    pub checkpoint: PathBuf,
+    /// Number of worker threads, overriding the one in the config [default: number of cores]
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+    /// Seed for initialization and shuffling, overriding the one in the config
+    #[arg(long, global = true)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Train the network (the default)
+    Train {
+        /// Continue from the last checkpoint
+        #[arg(long)]
+        resume: bool,
+        /// Delete the checkpoints of a previous run and start a new one
+        #[arg(long, conflicts_with = "resume")]
+        fresh: bool,
+    },
+    /// Evaluate the network on the test dataset
+    Test,
+    /// Show the network's prediction for images from the test dataset
+    Predict {
+        /// Indices of the images in the test dataset
+        #[arg(required = true)]
+        indices: Vec<usize>,
+    },
+    /// Print the architecture and parameter statistics of a model file
+    Inspect,
+    /// Compare backpropagation against numerical gradients
+    Gradcheck {
+        /// Number of test images to check
+        #[arg(long, default_value_t = 1)]
+        samples: usize,
+    },
+}
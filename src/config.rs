@@ -1,31 +1,427 @@
-pub fn load_config() -> anyhow::Result<Config> {
+pub fn load_config(path: impl AsRef<std::path::Path>) -> anyhow::Result<Config> {
     use std::io::Read;
 
-    let mut f = std::fs::File::open("config.ron")?;
+    let mut f = std::fs::File::open(path)?;
     let mut s = String::new();
     f.read_to_string(&mut s)?;
 
-    ron::from_str(&s).map_err(Into::into)
+    let config: Config = ron::from_str(&s)?;
+    if config.momentum_decay.is_some() {
+        anyhow::bail!(
+            "`momentum_decay` is no longer a top-level setting; \
+             use `optimizer: Nesterov(momentum_decay: ...)` instead"
+        );
+    }
+    if !(0.0..).contains(&config.learning_rate) {
+        anyhow::bail!("`learning_rate` must not be negative");
+    }
+    config.schedule.validate()?;
+    config.optimizer.validate()?;
+    let regularization = &config.regularization;
+    if !(0.0..).contains(&regularization.l1) || !(0.0..).contains(&regularization.l2) {
+        anyhow::bail!("`l1` and `l2` regularization must not be negative");
+    }
+    if regularization.max_norm.is_some_and(|max| !positive(max)) {
+        anyhow::bail!("`max_norm` must be positive");
+    }
+    let clipping = [config.clipping.value, config.clipping.norm];
+    if clipping
+        .into_iter()
+        .flatten()
+        .any(|max| !positive(max) || max.is_infinite())
+    {
+        anyhow::bail!("gradient clipping limits must be positive and finite");
+    }
+    if !(0.0..).contains(&config.warmup_epochs) {
+        anyhow::bail!("`warmup_epochs` must not be negative");
+    }
+    if config.batch_size == 0 {
+        anyhow::bail!("`batch_size` must be positive");
+    }
+    if config.batch_norm_group == 0 {
+        anyhow::bail!("`batch_norm_group` must be positive");
+    }
+    if !(0.0..1.0).contains(&config.validation_fraction) {
+        anyhow::bail!("`validation_fraction` must be at least 0 and less than 1");
+    }
+    if config.checkpoint_interval == Some(0) {
+        anyhow::bail!("`checkpoint_interval` must be positive");
+    }
+    if config.patience == Some(0) {
+        anyhow::bail!("`patience` must be positive");
+    }
+    Ok(config)
 }
 
-#[derive(serde::Deserialize)]
+/// Whether `n` is positive (which NaN isn't).
+fn positive(n: f64) -> bool {
+    n > 0.0
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
 pub struct Config {
     pub data: Datasets,
-    pub h_layers: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conv_layers: Vec<ConvLayer>,
+    pub h_layers: Vec<Dense>,
+    #[serde(default = "defaults::output_activation")]
+    pub output_activation: String,
+    /// Initialization of layers that don't specify their own.
+    #[serde(default)]
+    pub init: Init,
     pub learning_rate: f64,
-    pub momentum_decay: f64,
+    #[serde(default)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub warmup_epochs: f64,
+    #[serde(default)]
+    pub optimizer: OptimizerConfig,
+    /// Replaced by [`OptimizerConfig::Nesterov`], and only read so that
+    /// configs still setting it are rejected rather than silently ignored.
+    #[serde(default, skip_serializing, deserialize_with = "deserialize_some")]
+    momentum_decay: Option<f64>,
+    #[serde(default)]
+    pub regularization: Regularization,
+    #[serde(default)]
+    pub clipping: Clipping,
     pub batch_size: usize,
+    /// Number of samples batch normalization layers normalize together in
+    /// training.
+    #[serde(default = "defaults::batch_norm_group")]
+    pub batch_norm_group: usize,
     pub epochs: usize,
+    #[serde(default)]
+    pub validation_fraction: f64,
+    #[serde(default)]
+    pub patience: Option<usize>,
+    /// Not part of [`Config::hash`], as checkpoints store the seed of their
+    /// run.
+    #[serde(default, skip_serializing)]
+    pub seed: Option<u64>,
+    /// Not part of [`Config::hash`], as it doesn't affect training.
+    #[serde(default, skip_serializing)]
+    pub checkpoint_interval: Option<usize>,
+    /// Not part of [`Config::hash`], as it doesn't affect training.
+    #[serde(default, skip_serializing)]
+    pub checkpoint_retention: Retention,
+    /// Not part of [`Config::hash`], so that a run can be resumed on a
+    /// different machine.
+    #[serde(default, skip_serializing)]
+    pub threads: Option<usize>,
 }
 
-#[derive(serde::Deserialize)]
+impl Config {
+    pub fn hash(&self) -> u32 {
+        crc32fast::hash(ron::to_string(self).unwrap().as_bytes())
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
 pub struct Datasets {
     pub train: Dataset,
     pub test: Dataset,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, serde::Serialize)]
 pub struct Dataset {
     pub labels: String,
     pub images: String,
 }
+
+/// A hidden layer, written either as just its size or as a struct.
+#[derive(serde::Serialize)]
+pub struct Dense {
+    pub size: usize,
+    pub activation: String,
+    pub dropout: f64,
+    /// Normalization applied before the activation function, `batch` or
+    /// `layer`.
+    pub norm: Option<String>,
+    pub init: Option<Init>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DenseFields {
+    size: usize,
+    #[serde(default = "defaults::activation")]
+    activation: String,
+    #[serde(default)]
+    dropout: f64,
+    #[serde(default)]
+    norm: Option<String>,
+    #[serde(default)]
+    init: Option<Init>,
+}
+
+// Not `#[serde(untagged)]`, which can't hold enums such as `Init`.
+impl<'de> serde::Deserialize<'de> for Dense {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Dense;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a layer size or struct")
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, size: u64) -> Result<Dense, E> {
+                Ok(Dense {
+                    size: size as usize,
+                    activation: defaults::activation(),
+                    dropout: 0.0,
+                    norm: None,
+                    init: None,
+                })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Dense, A::Error> {
+                let fields: DenseFields = serde::Deserialize::deserialize(
+                    serde::de::value::MapAccessDeserializer::new(map),
+                )?;
+                Ok(Dense {
+                    size: fields.size,
+                    activation: fields.activation,
+                    dropout: fields.dropout,
+                    norm: fields.norm,
+                    init: fields.init,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// A layer applied to the image before the hidden layers.
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub enum ConvLayer {
+    Conv {
+        channels: usize,
+        kernel: usize,
+        #[serde(default = "defaults::stride")]
+        stride: usize,
+        #[serde(default)]
+        padding: usize,
+        #[serde(default = "defaults::activation")]
+        activation: String,
+        #[serde(default)]
+        dropout: f64,
+        #[serde(default)]
+        init: Option<Init>,
+    },
+    /// `stride` defaults to `size`.
+    MaxPool {
+        size: usize,
+        #[serde(default)]
+        stride: Option<usize>,
+    },
+    AvgPool {
+        size: usize,
+        #[serde(default)]
+        stride: Option<usize>,
+    },
+    Flatten,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy)]
+pub enum OptimizerConfig {
+    Sgd,
+    Nesterov {
+        #[serde(default = "defaults::momentum_decay")]
+        momentum_decay: f64,
+    },
+    Adam {
+        #[serde(default = "defaults::beta1")]
+        beta1: f64,
+        #[serde(default = "defaults::beta2")]
+        beta2: f64,
+        #[serde(default = "defaults::epsilon")]
+        epsilon: f64,
+        #[serde(default)]
+        weight_decay: f64,
+    },
+    AdamW {
+        #[serde(default = "defaults::beta1")]
+        beta1: f64,
+        #[serde(default = "defaults::beta2")]
+        beta2: f64,
+        #[serde(default = "defaults::epsilon")]
+        epsilon: f64,
+        #[serde(default = "defaults::weight_decay")]
+        weight_decay: f64,
+    },
+    RmsProp {
+        #[serde(default = "defaults::rms_decay")]
+        decay: f64,
+        #[serde(default = "defaults::epsilon")]
+        epsilon: f64,
+        #[serde(default)]
+        weight_decay: f64,
+    },
+    Adagrad {
+        #[serde(default = "defaults::epsilon")]
+        epsilon: f64,
+        #[serde(default)]
+        weight_decay: f64,
+    },
+}
+
+/// Distribution of a layer's initial weights. The normal and uniform
+/// variants of each scheme have the same variance.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+pub enum Init {
+    HeNormal,
+    #[default]
+    HeUniform,
+    XavierNormal,
+    XavierUniform,
+    LecunNormal,
+    LecunUniform,
+    Orthogonal,
+    Constant(f64),
+}
+
+/// Limits on the gradient, applied before each update.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+pub struct Clipping {
+    /// Maximum norm of the whole gradient, which is scaled down to it.
+    #[serde(default)]
+    pub norm: Option<f64>,
+    /// Maximum magnitude of each value of the gradient.
+    #[serde(default)]
+    pub value: Option<f64>,
+}
+
+/// Penalties on the size of the weights (but not biases).
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+pub struct Regularization {
+    #[serde(default)]
+    pub l1: f64,
+    #[serde(default)]
+    pub l2: f64,
+    /// Maximum norm of each neuron's incoming weights, enforced after
+    /// every update.
+    #[serde(default)]
+    pub max_norm: Option<f64>,
+}
+
+/// Which checkpoints to keep in the checkpoint directory.
+#[derive(serde::Deserialize, Clone, Copy)]
+pub struct Retention {
+    /// Number of most recent checkpoints.
+    #[serde(default = "defaults::keep_last")]
+    pub keep_last: usize,
+    /// Whether to also keep the checkpoint with the best validation cost.
+    #[serde(default = "defaults::keep_best")]
+    pub keep_best: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default)]
+pub enum Schedule {
+    #[default]
+    Constant,
+    Step {
+        step_size: usize,
+        #[serde(default = "defaults::gamma")]
+        gamma: f64,
+    },
+    Exponential {
+        gamma: f64,
+    },
+    Cosine {
+        period: f64,
+        #[serde(default)]
+        min_rate: f64,
+        #[serde(default = "defaults::period_mult")]
+        period_mult: f64,
+    },
+    OneCycle {
+        #[serde(default = "defaults::pct_start")]
+        pct_start: f64,
+        #[serde(default = "defaults::div")]
+        div: f64,
+        #[serde(default = "defaults::final_div")]
+        final_div: f64,
+    },
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep_last: defaults::keep_last(),
+            keep_best: defaults::keep_best(),
+        }
+    }
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        Self::Nesterov {
+            momentum_decay: defaults::momentum_decay(),
+        }
+    }
+}
+
+/// Reads an optional field written as a plain value rather than `Some(..)`.
+fn deserialize_some<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+mod defaults {
+    pub fn activation() -> String {
+        "relu".into()
+    }
+    pub fn output_activation() -> String {
+        "softmax".into()
+    }
+    pub fn stride() -> usize {
+        1
+    }
+    pub fn momentum_decay() -> f64 {
+        0.9
+    }
+    pub fn beta1() -> f64 {
+        0.9
+    }
+    pub fn beta2() -> f64 {
+        0.999
+    }
+    pub fn epsilon() -> f64 {
+        1.0e-8
+    }
+    pub fn weight_decay() -> f64 {
+        0.01
+    }
+    pub fn rms_decay() -> f64 {
+        0.9
+    }
+    pub fn gamma() -> f64 {
+        0.1
+    }
+    pub fn period_mult() -> f64 {
+        1.0
+    }
+    pub fn pct_start() -> f64 {
+        0.3
+    }
+    pub fn div() -> f64 {
+        25.0
+    }
+    pub fn final_div() -> f64 {
+        1.0e4
+    }
+    pub fn batch_norm_group() -> usize {
+        32
+    }
+    pub fn keep_last() -> usize {
+        1
+    }
+    pub fn keep_best() -> bool {
+        true
+    }
+}
@@ -0,0 +1,46 @@
+use crate::config::Init;
+use crate::network::Matrix;
+use rand::Rng;
+use rand_distr::StandardNormal;
+
+impl Init {
+    /// Fills `weights` for a layer where each input feeds `fan_out` outputs
+    /// and each output is computed from `fan_in` inputs.
+    pub fn fill(&self, weights: &mut Matrix, fan_in: usize, fan_out: usize, rng: &mut impl Rng) {
+        if weights.is_empty() {
+            return;
+        }
+        let (fan_in, fan_out) = (fan_in as f64, fan_out as f64);
+        let var = match *self {
+            Self::HeNormal | Self::HeUniform => 2.0 / fan_in,
+            Self::XavierNormal | Self::XavierUniform => 2.0 / (fan_in + fan_out),
+            Self::LecunNormal | Self::LecunUniform => 1.0 / fan_in,
+            Self::Orthogonal => return orthogonal(weights, rng),
+            Self::Constant(value) => return weights.fill(value),
+        };
+        if matches!(
+            self,
+            Self::HeNormal | Self::XavierNormal | Self::LecunNormal
+        ) {
+            let std = var.sqrt();
+            weights.apply(|w| *w = std * rng.sample::<f64, _>(StandardNormal));
+        } else {
+            let limit = (3.0 * var).sqrt();
+            weights.apply(|w| *w = rng.gen_range(-limit..limit));
+        }
+    }
+}
+
+/// Fills `weights` with a random matrix with orthonormal rows or columns,
+/// whichever there are fewer of.
+fn orthogonal(weights: &mut Matrix, rng: &mut impl Rng) {
+    let (rows, cols) = weights.shape();
+    let random = Matrix::from_fn(rows.max(cols), rows.min(cols), |_, _| {
+        rng.sample(StandardNormal)
+    });
+    let qr = random.qr();
+    let signs = qr.r().diagonal().map(f64::signum);
+    // Matching the signs of R's diagonal makes Q uniformly distributed.
+    let q = qr.q() * Matrix::from_diagonal(&signs);
+    *weights = if rows >= cols { q } else { q.transpose() };
+}
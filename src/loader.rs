@@ -1,10 +1,10 @@
 use crate::IMAGE_SIZE;
+use anyhow::Context;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
 
-const LABELS_MNUM: u32 = 0x00000801;
-const IMAGES_MNUM: u32 = 0x00000803;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 #[derive(Clone, Copy)]
 pub struct Image {
@@ -40,62 +40,291 @@ impl From<Image> for crate::network::Vector {
     }
 }
 
-pub fn load_labels(path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
-    let mut f = File::open(path)?;
-
-    let mut read_u32 = || -> std::io::Result<u32> {
-        let mut buf = [0u8; 4];
-        f.read_exact(&mut buf)?;
-        Ok(u32::from_be_bytes(buf))
-    };
+/// Opens an IDX file, decompressing it if it is gzipped (whatever its name).
+fn open(path: impl AsRef<Path>) -> anyhow::Result<Box<dyn Read>> {
+    let path = path.as_ref();
+    File::open(path)
+        .map(BufReader::new)
+        .and_then(decompress)
+        .with_context(|| format!("failed to open `{}`", path.display()))
+}
 
-    let mnum = read_u32()?;
-    if mnum != LABELS_MNUM {
-        anyhow::bail!("invalid labels mnum: {mnum} (expected {LABELS_MNUM})");
+/// `r`, decompressed if it starts with the gzip magic number.
+fn decompress(mut r: impl BufRead + 'static) -> std::io::Result<Box<dyn Read>> {
+    if r.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(flate2::bufread::MultiGzDecoder::new(r)))
+    } else {
+        Ok(Box::new(r))
     }
+}
 
-    let len = read_u32()? as usize;
+/// Contents of an IDX file: an array with dimensions `dims`, stored in
+/// row-major order.
+#[derive(Debug, PartialEq)]
+pub struct Idx {
+    pub dims: Vec<usize>,
+    pub data: IdxData,
+}
 
-    let mut label_bytes = Vec::with_capacity(len);
-    f.read_to_end(&mut label_bytes)?;
+// Only `U8` is used by the loaders below; the other types are only read in
+// the tests.
+#[derive(Debug, PartialEq)]
+pub enum IdxData {
+    U8(Vec<u8>),
+    #[cfg_attr(not(test), allow(dead_code))]
+    I8(Vec<i8>),
+    #[cfg_attr(not(test), allow(dead_code))]
+    I16(Vec<i16>),
+    #[cfg_attr(not(test), allow(dead_code))]
+    I32(Vec<i32>),
+    #[cfg_attr(not(test), allow(dead_code))]
+    F32(Vec<f32>),
+    #[cfg_attr(not(test), allow(dead_code))]
+    F64(Vec<f64>),
+}
 
-    Ok(label_bytes)
+impl IdxData {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::U8(_) => "u8",
+            Self::I8(_) => "i8",
+            Self::I16(_) => "i16",
+            Self::I32(_) => "i32",
+            Self::F32(_) => "f32",
+            Self::F64(_) => "f64",
+        }
+    }
 }
 
-pub fn load_images(path: impl AsRef<Path>) -> anyhow::Result<Vec<Image>> {
-    let mut f = File::open(path)?;
+pub fn load_idx(path: impl AsRef<Path>) -> anyhow::Result<Idx> {
+    let path = path.as_ref();
+    read_idx(open(path)?).with_context(|| format!("failed to read `{}`", path.display()))
+}
 
-    let mut read_u32 = || -> std::io::Result<u32> {
-        let mut buf = [0u8; 4];
-        f.read_exact(&mut buf)?;
-        Ok(u32::from_be_bytes(buf))
+fn read_idx(mut f: impl Read) -> anyhow::Result<Idx> {
+    let mut header = [0u8; 4];
+    f.read_exact(&mut header)?;
+    let [0, 0, type_code, ndims] = header else {
+        anyhow::bail!("not an IDX file (bad magic number)");
+    };
+    let size: usize = match type_code {
+        0x08 | 0x09 => 1,
+        0x0b => 2,
+        0x0c | 0x0d => 4,
+        0x0e => 8,
+        _ => anyhow::bail!("unknown IDX data type: {type_code:#04x}"),
     };
 
-    let mnum = read_u32()?;
-    if mnum != IMAGES_MNUM {
-        anyhow::bail!("invalid images mnum: {mnum} (expected {IMAGES_MNUM})");
-    }
-
-    let len = read_u32()? as usize;
+    let dims = (0..ndims)
+        .map(|_| {
+            let mut buf = [0u8; 4];
+            f.read_exact(&mut buf)
+                .map(|_| u32::from_be_bytes(buf) as usize)
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let len = dims
+        .iter()
+        .try_fold(size, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| anyhow::anyhow!("IDX dimensions {dims:?} are too large"))?;
 
-    let width = read_u32()? as usize;
-    let height = read_u32()? as usize;
-    if !(width == IMAGE_SIZE && height == IMAGE_SIZE) {
+    // Not allocating `len` up front, in case the header is corrupted.
+    let mut bytes = Vec::new();
+    f.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
         anyhow::bail!(
-            "invalid image size: {width}x{height} (expected {0}x{0})",
-            IMAGE_SIZE
+            "IDX file is truncated ({} bytes of data, expected {len})",
+            bytes.len()
         );
     }
 
-    let mut image_bytes = Vec::with_capacity(len);
-    f.read_to_end(&mut image_bytes)?;
-    let images = image_bytes
+    fn parse<T, const N: usize>(bytes: &[u8], from_be: fn([u8; N]) -> T) -> Vec<T> {
+        bytes
+            .chunks(N)
+            .map(|chunk| from_be(chunk.try_into().unwrap()))
+            .collect()
+    }
+    let data = match type_code {
+        0x08 => IdxData::U8(bytes),
+        0x09 => IdxData::I8(parse(&bytes, i8::from_be_bytes)),
+        0x0b => IdxData::I16(parse(&bytes, i16::from_be_bytes)),
+        0x0c => IdxData::I32(parse(&bytes, i32::from_be_bytes)),
+        0x0d => IdxData::F32(parse(&bytes, f32::from_be_bytes)),
+        _ => IdxData::F64(parse(&bytes, f64::from_be_bytes)),
+    };
+    Ok(Idx { dims, data })
+}
+
+pub fn load_labels(path: impl AsRef<Path>) -> anyhow::Result<Vec<u8>> {
+    let path = path.as_ref();
+    let idx = load_idx(path)?;
+    let labels = match (idx.data, idx.dims.as_slice()) {
+        (IdxData::U8(labels), [_]) => labels,
+        (data, dims) => anyhow::bail!(
+            "`{}` is not a list of labels (found {} data with dimensions {dims:?})",
+            path.display(),
+            data.type_name()
+        ),
+    };
+    if let Some(label) = labels.iter().find(|&&label| label > 9) {
+        anyhow::bail!("`{}` has an invalid label: {label}", path.display());
+    }
+    Ok(labels)
+}
+
+pub fn load_images(path: impl AsRef<Path>) -> anyhow::Result<Vec<Image>> {
+    let path = path.as_ref();
+    let idx = load_idx(path)?;
+    let pixels = match (idx.data, idx.dims.as_slice()) {
+        (IdxData::U8(pixels), [_, IMAGE_SIZE, IMAGE_SIZE]) => pixels,
+        (data, dims) => anyhow::bail!(
+            "`{}` is not a list of {IMAGE_SIZE}x{IMAGE_SIZE} images \
+             (found {} data with dimensions {dims:?})",
+            path.display(),
+            data.type_name()
+        ),
+    };
+    let images = pixels
         .chunks(IMAGE_SIZE.pow(2))
-        .map(|chunk| {
-            let mut arr = [0u8; IMAGE_SIZE.pow(2)];
-            arr.copy_from_slice(chunk);
-            Image { pixels: arr }
+        .map(|chunk| Image {
+            pixels: chunk.try_into().unwrap(),
         })
         .collect();
     Ok(images)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An IDX file with the given type code and dimensions, followed by
+    /// `data` as is.
+    fn idx(type_code: u8, dims: &[u32], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, type_code, dims.len() as u8];
+        dims.iter()
+            .for_each(|dim| bytes.extend_from_slice(&dim.to_be_bytes()));
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn be_bytes<const N: usize>(values: impl IntoIterator<Item = [u8; N]>) -> Vec<u8> {
+        values.into_iter().flatten().collect()
+    }
+
+    fn read(bytes: Vec<u8>) -> anyhow::Result<Idx> {
+        read_idx(decompress(std::io::Cursor::new(bytes))?)
+    }
+
+    #[test]
+    fn reads_every_data_type() {
+        let cases = [
+            (idx(0x08, &[3], &[0, 7, 255]), IdxData::U8(vec![0, 7, 255])),
+            (
+                idx(0x09, &[3], &[0xff, 0x80, 5]),
+                IdxData::I8(vec![-1, -128, 5]),
+            ),
+            (
+                idx(
+                    0x0b,
+                    &[2],
+                    &be_bytes([(-2i16).to_be_bytes(), 256i16.to_be_bytes()]),
+                ),
+                IdxData::I16(vec![-2, 256]),
+            ),
+            (
+                idx(
+                    0x0c,
+                    &[2],
+                    &be_bytes([i32::MIN.to_be_bytes(), 70000i32.to_be_bytes()]),
+                ),
+                IdxData::I32(vec![i32::MIN, 70000]),
+            ),
+            (
+                idx(
+                    0x0d,
+                    &[2],
+                    &be_bytes([0.5f32.to_be_bytes(), (-3.0f32).to_be_bytes()]),
+                ),
+                IdxData::F32(vec![0.5, -3.0]),
+            ),
+            (
+                idx(
+                    0x0e,
+                    &[2],
+                    &be_bytes([1.25f64.to_be_bytes(), (-1e300f64).to_be_bytes()]),
+                ),
+                IdxData::F64(vec![1.25, -1e300]),
+            ),
+        ];
+        for (bytes, data) in cases {
+            let expected = Idx {
+                dims: vec![data_len(&data)],
+                data,
+            };
+            assert_eq!(read(bytes).unwrap(), expected);
+        }
+    }
+
+    fn data_len(data: &IdxData) -> usize {
+        match data {
+            IdxData::U8(v) => v.len(),
+            IdxData::I8(v) => v.len(),
+            IdxData::I16(v) => v.len(),
+            IdxData::I32(v) => v.len(),
+            IdxData::F32(v) => v.len(),
+            IdxData::F64(v) => v.len(),
+        }
+    }
+
+    #[test]
+    fn reads_any_number_of_dimensions() {
+        let scalar = read(idx(0x08, &[], &[42])).unwrap();
+        assert_eq!(scalar.dims, Vec::<usize>::new());
+        assert_eq!(scalar.data, IdxData::U8(vec![42]));
+
+        let data = (0..24).collect::<Vec<u8>>();
+        let tensor = read(idx(0x08, &[2, 3, 1, 2, 2], &data)).unwrap();
+        assert_eq!(tensor.dims, [2, 3, 1, 2, 2]);
+        assert_eq!(tensor.data, IdxData::U8(data));
+
+        let empty = read(idx(0x0c, &[0, 5], &[])).unwrap();
+        assert_eq!(empty.dims, [0, 5]);
+        assert_eq!(empty.data, IdxData::I32(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let err = read(idx(0x0b, &[2, 2], &[0; 7])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "IDX file is truncated (7 bytes of data, expected 8)"
+        );
+        // A header cut off within the dimensions.
+        assert!(read(idx(0x08, &[2, 2], &[])[..8].to_vec()).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let err = read(vec![0x1f, 0x00, 0x08, 0x01, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.to_string(), "not an IDX file (bad magic number)");
+        let err = read(idx(0x0a, &[1], &[0])).unwrap_err();
+        assert_eq!(err.to_string(), "unknown IDX data type: 0x0a");
+        let err = read(idx(0x08, &[u32::MAX; 4], &[])).unwrap_err();
+        assert!(err.to_string().ends_with("are too large"));
+    }
+
+    #[test]
+    fn reads_gzipped_files() {
+        use std::io::Write;
+
+        let raw = idx(
+            0x0d,
+            &[2, 1],
+            &be_bytes([1.0f32.to_be_bytes(), 2.0f32.to_be_bytes()]),
+        );
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&raw).unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert_eq!(gzipped[..2], GZIP_MAGIC);
+        assert_eq!(read(gzipped).unwrap(), read(raw).unwrap());
+    }
+}
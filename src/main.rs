@@ -1,116 +1,712 @@
+mod checkpoint;
+mod cli;
 mod config;
+mod init;
 mod loader;
+mod metrics;
+mod model;
 mod network;
+mod optim;
+mod schedule;
 mod thread;
 
+use anyhow::Context;
+use clap::Parser;
 use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 const IMAGE_SIZE: usize = 28;
+/// Number of samples of a training batch in each job, whatever the number of
+/// threads, so that the chunks (and so their dropout masks and the order
+/// their results are summed in) don't depend on it.
+const CHUNK_SIZE: usize = 32;
+/// Consecutive batches with a non-finite cost or gradient after which
+/// training is rolled back to the last checkpoint.
+const MAX_BAD_BATCHES: usize = 3;
+/// Number of rollbacks after which training gives up.
+const MAX_ROLLBACKS: usize = 10;
+const INPUT: network::Shape = network::Shape {
+    channels: 1,
+    height: IMAGE_SIZE,
+    width: IMAGE_SIZE,
+};
+
+/// Workers return the summed gradient and batch normalization statistics of
+/// their chunk of samples (when requested) along with an evaluation of the
+/// network on it.
+type Pool = thread::ThreadPool<
+    (
+        Option<(network::Vector, network::Vector)>,
+        metrics::Evaluation,
+    ),
+    network::Network,
+>;
 
 fn main() -> anyhow::Result<()> {
-    let config = config::load_config()?;
+    let mut cli = cli::Cli::parse();
+    match cli.command.take().unwrap_or(cli::Command::Train {
+        resume: false,
+        fresh: false,
+    }) {
+        cli::Command::Train { resume, fresh } => train(&cli, resume, fresh),
+        cli::Command::Test => test(&cli),
+        cli::Command::Predict { indices } => predict(&cli, &indices),
+        cli::Command::Inspect => inspect(&cli),
+        cli::Command::Gradcheck { samples } => gradcheck(&cli, samples),
+    }
+}
+
+fn load_config(cli: &cli::Cli) -> anyhow::Result<config::Config> {
+    let mut config = config::load_config(&cli.config)?;
+    config.seed = cli.seed.or(config.seed);
+    config.threads = cli.threads.or(config.threads);
+    if config.threads == Some(0) {
+        anyhow::bail!("the number of threads must be positive");
+    }
+    Ok(config)
+}
 
-    let labels = loader::load_labels(&config.data.train.labels)?;
-    let images = loader::load_images(&config.data.train.images)?;
+/// Loads the labels and images of `dataset`, which must have the same count.
+fn load_dataset(dataset: &config::Dataset) -> anyhow::Result<(Vec<u8>, Vec<loader::Image>)> {
+    let labels = loader::load_labels(&dataset.labels)?;
+    let images = loader::load_images(&dataset.images)?;
+    if labels.len() != images.len() {
+        anyhow::bail!(
+            "`{}` has {} labels, but `{}` has {} images",
+            dataset.labels,
+            labels.len(),
+            dataset.images,
+            images.len()
+        );
+    }
+    Ok((labels, images))
+}
 
-    let mut layers = Vec::with_capacity(config.h_layers.len() + 2);
-    layers.push(IMAGE_SIZE.pow(2));
-    layers.extend_from_slice(&config.h_layers);
-    layers.push(10);
+/// Number of worker threads, by default one per core.
+fn threads(config: &config::Config) -> usize {
+    config.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    })
+}
 
-    let mut nn = network::Network::new(&layers);
-    if let Ok(s) = std::fs::read("network") {
-        nn.conf.write().unwrap().load_iter(s.chunks(8).map(|chunk| {
-            let mut bytes = [0; 8];
-            bytes.copy_from_slice(chunk);
-            f64::from_be_bytes(bytes)
-        }));
+/// Loads the model, or initializes a new network from `seed` if there is none.
+fn load_or_init_network(
+    cli: &cli::Cli,
+    config: &config::Config,
+    seed: u64,
+) -> anyhow::Result<network::Network> {
+    if !cli.model.exists() {
+        let mut rng = seeded_rng(seed, Stream::Init, &[]);
+        return network::Network::new(INPUT, &architecture(config)?, &mut rng);
     }
+    load_network(cli, config)
+}
 
-    let pool = thread::ThreadPool::new(16, || network::Network {
-        conf: std::sync::Arc::clone(&nn.conf),
-        state: nn.state.clone(),
-    });
+/// Loads the model, which must already exist.
+fn load_network(cli: &cli::Cli, config: &config::Config) -> anyhow::Result<network::Network> {
+    if !cli.model.exists() {
+        anyhow::bail!(
+            "model `{}` does not exist; run `train` to create it",
+            cli.model.display()
+        );
+    }
+    let conf = model::load(&cli.model)?;
+    check_architecture(&conf, config, &cli.model)?;
+    Ok(network::Network::from_conf(conf))
+}
 
-    let nbatches = (labels.len() + config.batch_size - 1) / config.batch_size;
-    let mut momentum = 0.0 * nn.conf.read().unwrap().flatten();
-
-    if std::env::args().nth(1).as_deref() != Some("--test") {
-        for _ in 0..config.epochs {
-            let mut ordering = (0..labels.len()).collect::<Vec<_>>();
-            ordering.shuffle(&mut rand::thread_rng());
-
-            print!("\n\n");
-
-            let mut overall_avg_cost = 0.0;
-            for (i, batch) in ordering.chunks(config.batch_size).enumerate() {
-                batch.iter().copied().for_each(|i| {
-                    let label = labels[i];
-                    let image = images[i];
-                    pool.execute(move |nn: &mut network::Network| {
-                        let input_vector = image.into();
-                        let expected = expected(label);
-                        nn.process(&input_vector);
-                        Some((nn.gradient(&expected), nn.cost(&expected)))
-                    });
-                });
-                let (gradients, costs): (Vec<_>, Vec<_>) = pool.results(batch.len()).unzip();
-                let len = gradients.len() as f64;
-                let avg = gradients
-                    .into_iter()
-                    .sum::<network::Vector>()
-                    .map(|n| n / len);
-                let avg_cost = costs.into_iter().sum::<f64>() / len;
-                let scaled_gradient = config.learning_rate * avg;
-                momentum = config.momentum_decay * momentum + &scaled_gradient;
-                let step = config.momentum_decay * &momentum + scaled_gradient;
-                let mut conf = nn.conf.write().unwrap();
-                let flattened = conf.flatten();
-                conf.load_iter((flattened - &step).iter().copied());
-                overall_avg_cost += avg_cost / nbatches as f64;
+/// Independent sequences of random numbers used in a run.
+#[derive(Clone, Copy)]
+enum Stream {
+    Init,
+    Shuffle,
+    Dropout,
+}
+
+/// Random number generator for one part of a run, such as the dropout masks
+/// of one chunk of a batch (identified by epoch, batch and chunk), so that
+/// each part is reproducible from the run's seed on its own.
+fn seeded_rng(seed: u64, stream: Stream, path: &[u64]) -> rand::rngs::StdRng {
+    use rand::Rng;
+    let seed = std::iter::once(stream as u64)
+        .chain(path.iter().copied())
+        .fold(seed, |seed, n| {
+            rand::rngs::StdRng::seed_from_u64(seed ^ n).gen()
+        });
+    rand::rngs::StdRng::seed_from_u64(seed)
+}
+
+/// The layers described by `config`, each with the initializer of its
+/// weights.
+fn architecture(
+    config: &config::Config,
+) -> anyhow::Result<Vec<(network::LayerSpec, config::Init)>> {
+    use network::LayerKind;
+
+    let activation = |name: &str| {
+        network::Activation::from_name(name).ok_or_else(|| {
+            let names = network::Activation::ALL
+                .iter()
+                .map(|act| act.name)
+                .collect::<Vec<_>>();
+            anyhow::anyhow!(
+                "unknown activation function `{name}` (expected one of: {})",
+                names.join(", ")
+            )
+        })
+    };
+
+    let plain = |kind| {
+        (
+            network::LayerSpec {
+                kind,
+                activation: network::Activation::IDENTITY,
+            },
+            config.init,
+        )
+    };
+    // No layer is added for a rate of zero, but any other rate is, so that
+    // the layer rejects rates out of range.
+    let dropout = |rate: f64| (rate != 0.0).then(|| plain(LayerKind::Dropout { rate }));
+
+    let mut layers = Vec::with_capacity(config.conv_layers.len() + config.h_layers.len() + 1);
+    for layer in &config.conv_layers {
+        layers.push(match layer {
+            config::ConvLayer::Conv {
+                channels,
+                kernel,
+                stride,
+                padding,
+                activation: name,
+                init,
+                ..
+            } => (
+                network::LayerSpec {
+                    kind: LayerKind::Conv {
+                        channels: *channels,
+                        kernel: *kernel,
+                        stride: *stride,
+                        padding: *padding,
+                    },
+                    activation: activation(name)?,
+                },
+                init.unwrap_or(config.init),
+            ),
+            config::ConvLayer::MaxPool { size, stride } => plain(LayerKind::MaxPool {
+                size: *size,
+                stride: stride.unwrap_or(*size),
+            }),
+            config::ConvLayer::AvgPool { size, stride } => plain(LayerKind::AvgPool {
+                size: *size,
+                stride: stride.unwrap_or(*size),
+            }),
+            config::ConvLayer::Flatten => plain(LayerKind::Flatten),
+        });
+        if let config::ConvLayer::Conv { dropout: rate, .. } = layer {
+            layers.extend(dropout(*rate));
+        }
+    }
+    for layer in &config.h_layers {
+        let dense = LayerKind::Dense { size: layer.size };
+        let activation = activation(&layer.activation)?;
+        let init = layer.init.unwrap_or(config.init);
+        match layer.norm.as_deref() {
+            Some(norm) => layers.extend([
+                (
+                    network::LayerSpec {
+                        kind: dense,
+                        activation: network::Activation::IDENTITY,
+                    },
+                    init,
+                ),
+                (
+                    network::LayerSpec {
+                        kind: match norm {
+                            "batch" => LayerKind::BatchNorm,
+                            "layer" => LayerKind::LayerNorm,
+                            _ => anyhow::bail!(
+                                "unknown normalization `{norm}` (expected one of: batch, layer)"
+                            ),
+                        },
+                        activation,
+                    },
+                    init,
+                ),
+            ]),
+            None => layers.push((
+                network::LayerSpec {
+                    kind: dense,
+                    activation,
+                },
+                init,
+            )),
+        }
+        layers.extend(dropout(layer.dropout));
+    }
+    layers.push((
+        network::LayerSpec {
+            kind: LayerKind::Dense { size: 10 },
+            activation: activation(&config.output_activation)?,
+        },
+        config.init,
+    ));
+
+    Ok(layers)
+}
+
+fn check_architecture(
+    conf: &network::NetConf,
+    config: &config::Config,
+    path: &std::path::Path,
+) -> anyhow::Result<()> {
+    let specs = architecture(config)?
+        .into_iter()
+        .map(|(spec, _)| spec)
+        .collect::<Vec<_>>();
+    if conf.input().len() != INPUT.len() || conf.specs() != specs {
+        let describe = |specs: &[network::LayerSpec]| {
+            specs
+                .iter()
+                .map(|spec| spec.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        anyhow::bail!(
+            "`{}` has layers [{}], but the config specifies [{}]",
+            path.display(),
+            describe(&conf.specs()),
+            describe(&specs)
+        );
+    }
+    Ok(())
+}
+
+fn train(cli: &cli::Cli, resume: bool, fresh: bool) -> anyhow::Result<()> {
+    let config = load_config(cli)?;
+
+    let (mut labels, mut images) = load_dataset(&config.data.train)?;
+    let validation = if config.validation_fraction > 0.0 {
+        let count = (labels.len() as f64 * config.validation_fraction).round() as usize;
+        if count == 0 {
+            anyhow::bail!(
+                "a `validation_fraction` of {} leaves no validation samples out of {}",
+                config.validation_fraction,
+                labels.len()
+            );
+        }
+        let split = labels.len() - count.min(labels.len());
+        Some(Samples::new(
+            labels.split_off(split),
+            images.split_off(split),
+        ))
+    } else {
+        None
+    };
+    if labels.is_empty() {
+        anyhow::bail!("there are no training samples");
+    }
+    let samples = Samples::new(labels, images);
+
+    let mut store = checkpoint::Store::open(&cli.checkpoint, config.checkpoint_retention)?;
+    let resumed = if resume {
+        let checkpoint = store.latest()?;
+        if checkpoint.config_hash != config.hash() {
+            anyhow::bail!(
+                "config has changed since checkpoint `{}` was written; \
+                 restore the original config to resume",
+                cli.checkpoint.display()
+            );
+        }
+        Some(checkpoint)
+    } else if store.is_empty() || fresh {
+        None
+    } else {
+        anyhow::bail!(
+            "`{}` holds checkpoints of a previous run; \
+             use `train --resume` to continue it or `train --fresh` to delete them",
+            cli.checkpoint.display()
+        );
+    };
+
+    let seed = match (&resumed, config.seed) {
+        (Some(checkpoint), Some(seed)) if seed != checkpoint.seed => {
+            println!(
+                "ignoring seed {seed}; resuming with the checkpoint's seed {}",
+                checkpoint.seed
+            );
+            checkpoint.seed
+        }
+        (Some(checkpoint), _) => checkpoint.seed,
+        (None, Some(seed)) => seed,
+        (None, None) => {
+            let seed = rand::random();
+            println!("using random seed {seed}");
+            seed
+        }
+    };
+    let loaded = resumed.is_some() || cli.model.exists();
+    let mut nn = match &resumed {
+        Some(checkpoint) => {
+            check_architecture(&checkpoint.conf, &config, &cli.checkpoint)?;
+            network::Network::from_conf(checkpoint.conf.clone())
+        }
+        None => load_or_init_network(cli, &config, seed)?,
+    };
+    // Only once the new run is known to be able to start.
+    if fresh {
+        let removed = store.clear()?;
+        if removed > 0 {
+            println!(
+                "deleted {removed} checkpoints of a previous run from `{}`",
+                cli.checkpoint.display()
+            );
+        }
+    }
+
+    let threads = threads(&config);
+    nn.norm_group = Some(config.batch_norm_group);
+    let pool = new_pool(threads, &nn);
+    // Batch normalization groups are never split between chunks.
+    let batch_norm = !nn.conf.read().unwrap().running_stats().is_empty();
+    let group = if batch_norm {
+        config.batch_norm_group
+    } else {
+        1
+    };
+    let chunk_size = CHUNK_SIZE.div_ceil(group) * group;
+
+    let nbatches = samples.len().div_ceil(config.batch_size);
+    let mut optimizer = config.optimizer.build(&nn.conf.read().unwrap());
+
+    let config_hash = config.hash();
+    let mut progress = match resumed {
+        Some(checkpoint) => {
+            optimizer.load_state(checkpoint.optimizer)?;
+            println!(
+                "resuming from epoch {}, batch {}",
+                checkpoint.progress.epoch + 1,
+                checkpoint.progress.batch
+            );
+            checkpoint.progress
+        }
+        None => checkpoint::Progress::default(),
+    };
+    if let (true, 0, 0, Some(validation)) = (loaded, progress.epoch, progress.batch, &validation) {
+        let evaluation = evaluate(&pool, validation)?;
+        let val_cost = evaluation.avg_cost();
+        println!(
+            "initial validation cost: {val_cost}; accuracy: {}%",
+            evaluation.accuracy()
+        );
+        progress.best_cost = progress.best_cost.or(Some(val_cost));
+    }
+
+    let snapshot = |nn: &network::Network,
+                    optimizer: &dyn optim::Optimizer,
+                    progress: checkpoint::Progress| {
+        checkpoint::Checkpoint {
+            conf: nn.conf.read().unwrap().clone(),
+            optimizer: optimizer.state(),
+            config_hash,
+            seed,
+            progress,
+        }
+    };
+    let mut save_checkpoint = |nn: &network::Network,
+                               optimizer: &dyn optim::Optimizer,
+                               progress: checkpoint::Progress,
+                               best: bool| {
+        let checkpoint = snapshot(nn, optimizer, progress);
+        store.save(&checkpoint, best).map(|_| checkpoint)
+    };
+    // What training is rolled back to if it diverges.
+    let mut last_good = snapshot(&nn, &*optimizer, progress);
+    let mut bad_batches = 0;
+
+    // The first signal stops training after the current batch, and a second
+    // one right away.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = Arc::clone(&interrupted);
+        move || {
+            if interrupted.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+        }
+    })?;
+
+    for epoch in progress.epoch..config.epochs {
+        let mut ordering = (0..samples.len()).collect::<Vec<_>>();
+        ordering.shuffle(&mut seeded_rng(seed, Stream::Shuffle, &[epoch as u64]));
+        let ordering: Arc<[usize]> = ordering.into();
+
+        print!("\n\n");
+
+        let skip = progress.batch;
+        for (i, batch) in chunks(0..ordering.len(), config.batch_size)
+            .enumerate()
+            .skip(skip)
+        {
+            if interrupted.load(Ordering::SeqCst) {
+                // Unless training has only just started, the last checkpoint
+                // is still current, and re-saving it would drop its best flag.
+                let position = |p: &checkpoint::Progress| (p.epoch, p.batch);
+                if position(&progress) == (0, 0)
+                    || position(&progress) != position(&last_good.progress)
+                {
+                    save_checkpoint(&nn, &*optimizer, progress, false)?;
+                }
                 println!(
-                    "\x1b[2Abatch {}/{} complete; avg cost: {}\n{}\x1b[K",
-                    i + 1,
-                    nbatches,
-                    avg_cost,
-                    gen_bar((avg_cost * 100.0) as usize),
+                    "interrupted after batch {i}/{nbatches} of epoch {}/{}; \
+                     checkpoint saved to `{}`",
+                    epoch + 1,
+                    config.epochs,
+                    cli.checkpoint.display()
                 );
+                if let Some(best) = progress.best_cost {
+                    println!("best validation cost: {best}");
+                }
+                println!("run `train --resume` to continue");
+                return Ok(());
+            }
+            chunks(batch.clone(), chunk_size)
+                .enumerate()
+                .for_each(|(j, chunk)| {
+                    let rng =
+                        seeded_rng(seed, Stream::Dropout, &[epoch as u64, i as u64, j as u64]);
+                    execute_chunk(&pool, &samples, &ordering, chunk, Some(rng));
+                });
+            let mut evaluation = metrics::Evaluation::default();
+            let (gradient, stats) = pool
+                .results(batch.len().div_ceil(chunk_size))
+                .with_context(|| format!("batch {} of epoch {} failed", i + 1, epoch + 1))?
+                .map(|(update, chunk_evaluation)| {
+                    evaluation.merge(&chunk_evaluation);
+                    update.unwrap()
+                })
+                .reduce(|(g1, s1), (g2, s2)| (g1 + g2, s1 + s2))
+                .unwrap();
+            let avg_cost = evaluation.avg_cost();
+            let gradient = gradient / batch.len() as f64;
+            progress.batch = i + 1;
+            if !avg_cost.is_finite() || gradient.iter().any(|n| !n.is_finite()) {
+                warn(&format!(
+                    "batch {}/{nbatches}: cost or gradient is not finite; skipping",
+                    i + 1
+                ));
+                bad_batches += 1;
+                if bad_batches >= MAX_BAD_BATCHES {
+                    warn(&rollback(&nn, &mut *optimizer, &last_good, &mut progress)?);
+                    bad_batches = 0;
+                }
+                continue;
             }
+            bad_batches = 0;
 
-            println!("overall avg cost: {}", overall_avg_cost);
+            let epochs_done = epoch as f64 + i as f64 / nbatches as f64;
+            // Counting this batch as done, so the first update isn't wasted.
+            let warmup_done = epochs_done + 1.0 / nbatches as f64;
+            let learning_rate =
+                config
+                    .schedule
+                    .rate(config.learning_rate, epochs_done, config.epochs as f64)
+                    * schedule::warmup(warmup_done, config.warmup_epochs)
+                    * 0.5f64.powi(progress.rollbacks as i32);
+            let mut conf = nn.conf.write().unwrap();
+            let penalty = config.regularization.penalty(&conf);
+            let mut gradient = gradient + config.regularization.gradient(&conf);
+            config.clipping.apply(&mut gradient);
+            optimizer.update(&mut conf, &gradient, learning_rate);
+            if let Some(max) = config.regularization.max_norm {
+                conf.clip_max_norm(max);
+            }
+            conf.update_running_stats(&stats, batch.len());
+            let diverged = !conf.is_finite();
+            drop(conf);
+            if diverged {
+                warn(&format!(
+                    "batch {}/{nbatches}: parameters are no longer finite",
+                    i + 1
+                ));
+                warn(&rollback(&nn, &mut *optimizer, &last_good, &mut progress)?);
+                continue;
+            }
+            progress.epoch_cost += avg_cost / nbatches as f64;
+            println!(
+                "\x1b[2Abatch {}/{} complete; avg cost: {}; penalty: {:.4e}; learning rate: {:.3e}\x1b[K\n{}\x1b[K",
+                i + 1,
+                nbatches,
+                avg_cost,
+                penalty,
+                learning_rate,
+                gen_bar((avg_cost * 100.0).min(800.0) as usize),
+            );
 
-            save(&nn.conf.read().unwrap())?;
+            if config
+                .checkpoint_interval
+                .is_some_and(|interval| (i + 1) % interval == 0 && i + 1 < nbatches)
+            {
+                last_good = save_checkpoint(&nn, &*optimizer, progress, false)?;
+            }
         }
-    } else {
-        let test_labels = loader::load_labels(&config.data.test.labels)?;
-        let test_images = loader::load_images(&config.data.test.images)?;
-
-        for (label, image) in test_labels.iter().zip(test_images.iter()).take(10) {
-            let input_vector = (*image).into();
-            nn.process(&input_vector);
-            print_info(*label, image, &nn);
-        }
-
-        let mut avg_cost = 0.0;
-        let mut accuracy = 0.0;
-        let count = test_labels.len() as f64;
-        for (label, image) in test_labels.into_iter().zip(test_images) {
-            let input_vector = image.into();
-            let expected = expected(label);
-            nn.process(&input_vector);
-            avg_cost += nn.cost(&expected) / count;
-            let output = nn
-                .output()
-                .iter()
-                .copied()
-                .enumerate()
-                .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
-                .unwrap()
-                .0;
-            accuracy += 100.0 * (output == label as usize) as u64 as f64 / count;
+
+        println!(
+            "overall avg cost: {}; penalty: {:.4e}",
+            progress.epoch_cost,
+            config.regularization.penalty(&nn.conf.read().unwrap())
+        );
+        progress.epoch = epoch + 1;
+        progress.batch = 0;
+        progress.epoch_cost = 0.0;
+
+        let Some(validation) = &validation else {
+            model::save(&nn.conf.read().unwrap(), &cli.model)?;
+            last_good = save_checkpoint(&nn, &*optimizer, progress, false)?;
+            continue;
+        };
+        let evaluation = evaluate(&pool, validation)?;
+        let val_cost = evaluation.avg_cost();
+        println!(
+            "validation cost: {val_cost}; accuracy: {}%",
+            evaluation.accuracy()
+        );
+        if !val_cost.is_finite() {
+            println!("validation cost is not finite");
+            println!(
+                "{}",
+                rollback(&nn, &mut *optimizer, &last_good, &mut progress)?
+            );
+            continue;
+        }
+        let best = progress.best_cost.is_none_or(|best| val_cost < best);
+        if best {
+            progress.best_cost = Some(val_cost);
+            progress.stale_epochs = 0;
+            model::save(&nn.conf.read().unwrap(), &cli.model)?;
+            println!("new best model saved");
+        } else {
+            progress.stale_epochs += 1;
+        }
+        last_good = save_checkpoint(&nn, &*optimizer, progress, best)?;
+        if config
+            .patience
+            .is_some_and(|patience| progress.stale_epochs >= patience)
+        {
+            println!(
+                "no improvement for {} epochs; stopping early",
+                progress.stale_epochs
+            );
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores the parameters and optimizer state of `last_good`, halving the
+/// learning rate from then on. Returns a message describing the rollback.
+fn rollback(
+    nn: &network::Network,
+    optimizer: &mut dyn optim::Optimizer,
+    last_good: &checkpoint::Checkpoint,
+    progress: &mut checkpoint::Progress,
+) -> anyhow::Result<String> {
+    progress.rollbacks += 1;
+    if progress.rollbacks > MAX_ROLLBACKS {
+        anyhow::bail!("training diverged {} times; giving up", progress.rollbacks);
+    }
+    *nn.conf.write().unwrap() = last_good.conf.clone();
+    optimizer.load_state(last_good.optimizer.clone())?;
+    Ok(format!(
+        "rolled back to the last checkpoint; learning rate scaled by {}",
+        0.5f64.powi(progress.rollbacks as i32)
+    ))
+}
+
+/// Prints `message` above the training progress display.
+fn warn(message: &str) {
+    println!("\x1b[2A{message}\x1b[K\n\x1b[K\n");
+}
+
+fn test(cli: &cli::Cli) -> anyhow::Result<()> {
+    let config = load_config(cli)?;
+    let mut nn = load_network(cli, &config)?;
+
+    let (test_labels, test_images) = load_dataset(&config.data.test)?;
+
+    for (label, image) in test_labels.iter().zip(test_images.iter()).take(10) {
+        let input_vector = (*image).into();
+        nn.process(&input_vector);
+        print_info(*label, image, &nn);
+    }
+
+    let pool = new_pool(threads(&config), &nn);
+    println!(
+        "{}",
+        evaluate(&pool, &Samples::new(test_labels, test_images))?
+    );
+
+    Ok(())
+}
+
+fn predict(cli: &cli::Cli, indices: &[usize]) -> anyhow::Result<()> {
+    let config = load_config(cli)?;
+    let mut nn = load_network(cli, &config)?;
+
+    let (test_labels, test_images) = load_dataset(&config.data.test)?;
+
+    for &i in indices {
+        let (Some(&label), Some(image)) = (test_labels.get(i), test_images.get(i)) else {
+            anyhow::bail!(
+                "image index {i} is out of range (the test dataset has {} images)",
+                test_images.len()
+            );
+        };
+        nn.process(&(*image).into());
+        print_info(label, image, &nn);
+    }
+
+    Ok(())
+}
+
+fn inspect(cli: &cli::Cli) -> anyhow::Result<()> {
+    let conf = model::load(&cli.model)?;
+    println!("model: {}", cli.model.display());
+    println!("layers: {:?}", conf.sizes());
+    println!("cost: {}", conf.cost().name);
+    println!("parameters: {}", conf.param_count());
+    let shapes = conf.shapes();
+    for (i, ((weights, biases), spec)) in conf.layer_params().zip(conf.specs()).enumerate() {
+        println!(
+            "layer {}: {} {} -> {} {}",
+            i + 1,
+            spec.kind,
+            shapes[i],
+            shapes[i + 1],
+            spec.activation.name
+        );
+        if weights.is_empty() {
+            continue;
         }
-        println!("avg cost: {avg_cost}");
-        println!("accuracy: {accuracy}%");
+        let (w_mean, w_std, w_min, w_max) = stats(weights.as_slice());
+        let (b_mean, b_std, b_min, b_max) = stats(biases.as_slice());
+        println!("  weights: mean {w_mean:.4e}, std {w_std:.4e}, min {w_min:.4e}, max {w_max:.4e}");
+        println!("  biases:  mean {b_mean:.4e}, std {b_std:.4e}, min {b_min:.4e}, max {b_max:.4e}");
+    }
+    Ok(())
+}
+
+fn gradcheck(cli: &cli::Cli, samples: usize) -> anyhow::Result<()> {
+    let config = load_config(cli)?;
+    let mut nn = load_or_init_network(cli, &config, config.seed.unwrap_or_else(rand::random))?;
+
+    let (test_labels, test_images) = load_dataset(&config.data.test)?;
+
+    for (label, image) in test_labels.iter().zip(test_images.iter()).take(samples) {
+        gradient_check(*label, image, &mut nn);
     }
 
     Ok(())
@@ -124,6 +720,101 @@ fn expected(n: u8) -> network::Vector {
     vector
 }
 
+/// Labels and images, shared with the pool's workers.
+#[derive(Clone)]
+struct Samples {
+    labels: Arc<[u8]>,
+    images: Arc<[loader::Image]>,
+}
+
+impl Samples {
+    fn new(labels: Vec<u8>, images: Vec<loader::Image>) -> Self {
+        Self {
+            labels: labels.into(),
+            images: images.into(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Input and expected output of the samples at `indices`, one column per
+    /// sample.
+    fn matrices(&self, indices: &[usize]) -> (network::Matrix, network::Matrix) {
+        let input = network::Matrix::from_fn(IMAGE_SIZE.pow(2), indices.len(), |r, c| {
+            self.images[indices[c]].pixels[r] as f64 / 0xff as f64
+        });
+        let expected = network::Matrix::from_fn(10, indices.len(), |r, c| {
+            (self.labels[indices[c]] as usize == r) as u64 as f64
+        });
+        (input, expected)
+    }
+}
+
+/// Splits `range` into consecutive ranges of at most `size`.
+fn chunks(range: Range<usize>, size: usize) -> impl Iterator<Item = Range<usize>> {
+    range
+        .clone()
+        .step_by(size)
+        .map(move |start| start..(start + size).min(range.end))
+}
+
+fn new_pool(threads: usize, nn: &network::Network) -> Pool {
+    thread::ThreadPool::new(threads, || network::Network {
+        conf: std::sync::Arc::clone(&nn.conf),
+        state: nn.state.clone(),
+        training: false,
+        rng: rand::SeedableRng::from_entropy(),
+        norm_group: nn.norm_group,
+    })
+}
+
+/// Evaluates the network in the pool on the samples at `indices[chunk]`. In
+/// training, `training` is the generator of the chunk's dropout masks, and
+/// the gradient is computed too.
+fn execute_chunk(
+    pool: &Pool,
+    samples: &Samples,
+    indices: &Arc<[usize]>,
+    chunk: Range<usize>,
+    training: Option<rand::rngs::StdRng>,
+) {
+    let samples = samples.clone();
+    let indices = Arc::clone(indices);
+    let gradient = training.is_some();
+    pool.execute(move |nn: &mut network::Network| {
+        let chunk = &indices[chunk];
+        let (input, expected) = samples.matrices(chunk);
+        nn.training = gradient;
+        if let Some(rng) = training {
+            nn.rng = rng;
+        }
+        nn.process_batch(&input);
+        let mut evaluation = metrics::Evaluation::default();
+        evaluation.add_batch(
+            nn.batch_output(),
+            chunk.iter().map(|&i| samples.labels[i]),
+            nn.batch_cost(&expected),
+        );
+        let update = gradient.then(|| (nn.batch_gradient(&expected), nn.batch_stats()));
+        Ok((update, evaluation))
+    });
+}
+
+/// Evaluates the network on `samples` in chunks of [`CHUNK_SIZE`], so that
+/// the pass is spread over the pool whatever the training batch size.
+fn evaluate(pool: &Pool, samples: &Samples) -> anyhow::Result<metrics::Evaluation> {
+    let indices: Arc<[usize]> = (0..samples.len()).collect();
+    chunks(0..samples.len(), CHUNK_SIZE)
+        .for_each(|chunk| execute_chunk(pool, samples, &indices, chunk, None));
+    let mut evaluation = metrics::Evaluation::default();
+    pool.results(samples.len().div_ceil(CHUNK_SIZE))
+        .context("evaluation failed")?
+        .for_each(|(_, chunk_evaluation)| evaluation.merge(&chunk_evaluation));
+    Ok(evaluation)
+}
+
 fn print_info(label: u8, image: &loader::Image, nn: &network::Network) {
     println!("{}", label);
     println!("{}", image);
@@ -165,20 +856,6 @@ fn print_info(label: u8, image: &loader::Image, nn: &network::Network) {
     println!("cost: {}", nn.cost(&expected(label)));
 }
 
-fn save(conf: &network::NetConf) -> std::io::Result<()> {
-    use std::io::Write;
-    let bytes = conf
-        .flatten()
-        .iter()
-        .copied()
-        .flat_map(|n| n.to_be_bytes().into_iter())
-        .collect::<Vec<_>>();
-    let mut file = std::fs::File::create("network")?;
-    file.write_all(&bytes)?;
-    Ok(())
-}
-
-#[allow(dead_code)]
 fn gradient_check(label: u8, image: &loader::Image, nn: &mut network::Network) {
     const VARIANCE: f64 = 1.0e-10;
 
@@ -206,21 +883,31 @@ fn gradient_check(label: u8, image: &loader::Image, nn: &mut network::Network) {
         flattened[i] += VARIANCE;
         actual[i] = (upper - lower) / (2.0 * VARIANCE);
     }
+    nn.conf
+        .write()
+        .unwrap()
+        .load_iter(flattened.iter().copied());
 
     let mut diff = &actual - &gradient;
-    println!("{}", diff.iter().copied().any(f64::is_nan));
     diff.apply(|n| *n = n.abs());
-    println!("{}", diff.iter().copied().any(f64::is_nan));
     (0..diff.nrows()).for_each(|i| {
         diff[i] /= actual[i].abs().max(gradient[i].abs()) + VARIANCE;
     });
-    println!("{}", diff.iter().copied().any(f64::is_nan));
 
+    let nan = diff.iter().copied().any(f64::is_nan);
     let sum = diff.sum();
     let avg = sum / diff.nrows() as f64;
     let max = diff.max();
-    println!("{}", diff.iter().copied().any(f64::is_nan));
-    println!("avg: {avg}, sum: {sum}, max: {max}");
+    println!("label {label}: relative error avg: {avg}, sum: {sum}, max: {max}, NaN: {nan}");
+}
+
+fn stats(values: &[f64]) -> (f64, f64, f64, f64) {
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let var = values.iter().map(|n| (n - mean).powi(2)).sum::<f64>() / count;
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    (mean, var.sqrt(), min, max)
 }
 
 fn gen_bar(n: usize) -> String {
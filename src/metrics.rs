@@ -0,0 +1,173 @@
+use crate::network::Matrix;
+
+const CLASSES: usize = 10;
+const TOP_K: [usize; 3] = [1, 3, 5];
+
+/// Running totals from evaluating the network on a set of samples.
+#[derive(Clone, Default)]
+pub struct Evaluation {
+    pub count: usize,
+    pub total_cost: f64,
+    /// `confusion[actual][predicted]`
+    pub confusion: [[u64; CLASSES]; CLASSES],
+    /// `ranks[r]` is the number of samples with `r` other outputs at
+    /// least as high as the label's. Ties and NaN count against the label.
+    pub ranks: [u64; CLASSES],
+}
+
+impl Evaluation {
+    pub fn add_batch(&mut self, outputs: &Matrix, labels: impl Iterator<Item = u8>, cost: f64) {
+        self.total_cost += cost;
+        for (output, label) in outputs.column_iter().zip(labels) {
+            let label = label as usize;
+            self.count += 1;
+            self.confusion[label][output.imax()] += 1;
+            let target = output[label];
+            let rank = if target.is_nan() {
+                CLASSES - 1
+            } else {
+                output
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, n)| i != label && (*n >= target || n.is_nan()))
+                    .count()
+            };
+            self.ranks[rank] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.total_cost += other.total_cost;
+        for (row, other_row) in self.confusion.iter_mut().zip(other.confusion.iter()) {
+            row.iter_mut().zip(other_row).for_each(|(n, m)| *n += m);
+        }
+        self.ranks
+            .iter_mut()
+            .zip(other.ranks.iter())
+            .for_each(|(n, m)| *n += m);
+    }
+
+    pub fn avg_cost(&self) -> f64 {
+        self.total_cost / self.count as f64
+    }
+
+    /// Accuracy as a percentage.
+    pub fn accuracy(&self) -> f64 {
+        let correct = (0..CLASSES).map(|c| self.confusion[c][c]).sum::<u64>();
+        100.0 * correct as f64 / self.count as f64
+    }
+
+    /// Percentage of samples whose label is among the `k` highest outputs.
+    pub fn top_k(&self, k: usize) -> f64 {
+        100.0 * self.ranks[..k].iter().sum::<u64>() as f64 / self.count as f64
+    }
+
+    pub fn precision(&self, class: usize) -> f64 {
+        let predicted = self.confusion.iter().map(|row| row[class]).sum::<u64>();
+        ratio(self.confusion[class][class], predicted)
+    }
+
+    pub fn recall(&self, class: usize) -> f64 {
+        let actual = self.confusion[class].iter().sum::<u64>();
+        ratio(self.confusion[class][class], actual)
+    }
+
+    pub fn f1(&self, class: usize) -> f64 {
+        f1(self.precision(class), self.recall(class))
+    }
+}
+
+impl std::fmt::Display for Evaluation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "avg cost: {}", self.avg_cost())?;
+        writeln!(f, "accuracy: {}%", self.accuracy())?;
+        for k in TOP_K.into_iter().skip(1) {
+            writeln!(f, "top-{k} accuracy: {}%", self.top_k(k))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "confusion matrix (rows: actual, columns: predicted)")?;
+        write!(f, "     ")?;
+        (0..CLASSES).try_for_each(|n| write!(f, "{n:>6}"))?;
+        writeln!(f)?;
+        for (actual, row) in self.confusion.iter().enumerate() {
+            let total = row.iter().sum::<u64>().max(1) as f64;
+            write!(f, "{actual:>5}")?;
+            for (predicted, &n) in row.iter().enumerate() {
+                let shade = (n as f64 / total * 0xff as f64) as u8;
+                let (r, g) = if actual == predicted {
+                    (0, shade)
+                } else {
+                    (shade, 0)
+                };
+                write!(f, "\x1b[48;2;{r};{g};0m{n:>6}\x1b[0m")?;
+            }
+            writeln!(f)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "class  precision  recall      f1")?;
+        for class in 0..CLASSES {
+            writeln!(
+                f,
+                "{class:>5}  {:>9.4}  {:>6.4}  {:>6.4}",
+                self.precision(class),
+                self.recall(class),
+                self.f1(class),
+            )?;
+        }
+        let macro_precision = (0..CLASSES).map(|c| self.precision(c)).sum::<f64>() / CLASSES as f64;
+        let macro_recall = (0..CLASSES).map(|c| self.recall(c)).sum::<f64>() / CLASSES as f64;
+        let macro_f1 = (0..CLASSES).map(|c| self.f1(c)).sum::<f64>() / CLASSES as f64;
+        writeln!(
+            f,
+            "macro  {macro_precision:>9.4}  {macro_recall:>6.4}  {macro_f1:>6.4}"
+        )?;
+        // Every sample has exactly one label and one prediction, so the
+        // micro averages all reduce to accuracy.
+        let micro = self.accuracy() / 100.0;
+        write!(f, "micro  {micro:>9.4}  {micro:>6.4}  {micro:>6.4}")
+    }
+}
+
+fn ratio(n: u64, d: u64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        n as f64 / d as f64
+    }
+}
+
+fn f1(precision: f64, recall: f64) -> f64 {
+    if precision + recall == 0.0 {
+        0.0
+    } else {
+        2.0 * precision * recall / (precision + recall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Vector;
+
+    #[test]
+    fn ties_count_against_the_label() {
+        let outputs = Matrix::from_columns(&[
+            Vector::zeros(CLASSES),
+            Vector::zeros(CLASSES),
+            Vector::from_element(CLASSES, f64::NAN),
+        ]);
+        let mut evaluation = Evaluation::default();
+        evaluation.add_batch(&outputs, [0, 7, 3].into_iter(), 0.0);
+
+        // Every column predicts class 0, which is only right for the first.
+        assert_eq!(evaluation.confusion[0][0], 1);
+        assert_eq!(evaluation.confusion[7][0], 1);
+        assert!((evaluation.accuracy() - 100.0 / 3.0).abs() < 1e-9);
+        assert_eq!(evaluation.top_k(1), 0.0);
+        assert_eq!(evaluation.top_k(5), 0.0);
+        assert_eq!(evaluation.ranks[CLASSES - 1], 3);
+    }
+}
@@ -0,0 +1,374 @@
+//! Model file format.
+//!
+//! All integers and parameters are big-endian.
+//!
+//! | field       | type                                      |
+//! |-------------|-------------------------------------------|
+//! | magic       | `b"DGNN"`                                 |
+//! | version     | `u32`                                     |
+//! | cost        | name                                      |
+//! | layer count | `u32`                                     |
+//! | input shape | channels, height, width, each `u32`       |
+//! | layers      | per layer: kind, kind fields, name        |
+//! | parameters  | `f64` in [`NetConf::flatten`] order       |
+//! | statistics  | `f64` in [`NetConf::running_stats`] order |
+//! | checksum    | CRC-32 of all preceding bytes, `u32`      |
+//!
+//! A layer's kind is a name followed by its fields, each a `u32` unless
+//! noted:
+//!
+//! | kind         | fields                            |
+//! |--------------|-----------------------------------|
+//! | `dense`      | size                              |
+//! | `conv`       | channels, kernel, stride, padding |
+//! | `max_pool`   | size, stride                      |
+//! | `avg_pool`   | size, stride                      |
+//! | `flatten`    |                                   |
+//! | `dropout`    | rate (`f64`)                      |
+//! | `batch_norm` |                                   |
+//! | `layer_norm` |                                   |
+//!
+//! The layer's activation name comes after its kind fields.
+//!
+//! Names are a `u8` length followed by that many UTF-8 bytes.
+
+use crate::network::{Activation, Cost, LayerKind, LayerSpec, NetConf, Shape};
+use anyhow::Context;
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"DGNN";
+const VERSION: u32 = 1;
+
+pub fn save(conf: &NetConf, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    if !conf.is_finite() {
+        anyhow::bail!("refusing to save a model with non-finite parameters");
+    }
+    write_atomic(path.as_ref(), &encode(conf))
+}
+
+/// Writes `bytes` to a temporary file next to `path` and renames it over
+/// `path`, so that an interrupted write leaves the previous file intact.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = Path::new(&tmp);
+    let mut file = std::fs::File::create(tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path).with_context(|| format!("failed to replace `{}`", path.display()))
+}
+
+pub fn load(path: impl AsRef<Path>) -> anyhow::Result<NetConf> {
+    let path = path.as_ref();
+    std::fs::read(path)
+        .map_err(Into::into)
+        .and_then(|bytes| decode(&bytes))
+        .with_context(|| format!("failed to load model `{}`", path.display()))
+}
+
+pub fn encode(conf: &NetConf) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    write_name(&mut bytes, conf.cost().name);
+
+    let specs = conf.specs();
+    bytes.extend_from_slice(&(specs.len() as u32).to_be_bytes());
+    let input = conf.input();
+    for n in [input.channels, input.height, input.width] {
+        bytes.extend_from_slice(&(n as u32).to_be_bytes());
+    }
+    for spec in &specs {
+        write_name(&mut bytes, spec.kind.name());
+        let fields = match spec.kind {
+            LayerKind::Dense { size } => vec![size],
+            LayerKind::Conv {
+                channels,
+                kernel,
+                stride,
+                padding,
+            } => vec![channels, kernel, stride, padding],
+            LayerKind::MaxPool { size, stride } | LayerKind::AvgPool { size, stride } => {
+                vec![size, stride]
+            }
+            LayerKind::Flatten | LayerKind::BatchNorm | LayerKind::LayerNorm => vec![],
+            LayerKind::Dropout { rate } => {
+                bytes.extend_from_slice(&rate.to_be_bytes());
+                vec![]
+            }
+        };
+        fields
+            .iter()
+            .for_each(|n| bytes.extend_from_slice(&(*n as u32).to_be_bytes()));
+        write_name(&mut bytes, spec.activation.name);
+    }
+
+    conf.flatten()
+        .iter()
+        .chain(conf.running_stats().iter())
+        .for_each(|n| bytes.extend_from_slice(&n.to_be_bytes()));
+
+    let checksum = crc32fast::hash(&bytes);
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<NetConf> {
+    if bytes.len() < MAGIC.len() || bytes[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("not a model file (bad magic number)");
+    }
+    if bytes.len() < MAGIC.len() + 8 {
+        anyhow::bail!("model file is truncated");
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    let mut r = Reader(&body[MAGIC.len()..]);
+
+    let version = r.u32()?;
+    if version != VERSION {
+        anyhow::bail!("unsupported model version: {version} (expected {VERSION})");
+    }
+
+    let checksum = u32::from_be_bytes(checksum.try_into().unwrap());
+    if checksum != crc32fast::hash(body) {
+        anyhow::bail!("model checksum mismatch (file is truncated or corrupted)");
+    }
+
+    let cost_name = r.name()?;
+    let cost = Cost::from_name(&cost_name)
+        .ok_or_else(|| anyhow::anyhow!("unknown cost function: {cost_name:?}"))?;
+
+    let layer_count = r.u32()? as usize;
+    if layer_count == 0 {
+        anyhow::bail!("model has no layers");
+    }
+    let input = Shape {
+        channels: r.u32()? as usize,
+        height: r.u32()? as usize,
+        width: r.u32()? as usize,
+    };
+    let mut specs = Vec::with_capacity(layer_count);
+    for _ in 0..layer_count {
+        let kind = read_kind(&mut r)?;
+        let name = r.name()?;
+        let activation = Activation::from_name(&name)
+            .ok_or_else(|| anyhow::anyhow!("unknown activation function: {name:?}"))?;
+        specs.push(LayerSpec { kind, activation });
+    }
+
+    let mut conf = NetConf::zeroed(input, &specs, cost)?;
+    let params = conf.param_count() * 8;
+    let expected = params + conf.running_stats().len() * 8;
+    if r.0.len() != expected {
+        anyhow::bail!(
+            "model parameter data is {} bytes (expected {expected} for layers {:?})",
+            r.0.len(),
+            conf.sizes()
+        );
+    }
+    let floats = |bytes: &'_ [u8]| {
+        bytes
+            .chunks(8)
+            .map(|chunk| f64::from_be_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    conf.load_iter(floats(&r.0[..params]).into_iter());
+    conf.load_running_stats(floats(&r.0[params..]).into_iter());
+
+    Ok(conf)
+}
+
+fn read_kind(r: &mut Reader) -> anyhow::Result<LayerKind> {
+    let name = r.name()?;
+    let mut field = || r.u32().map(|n| n as usize);
+    Ok(match name.as_str() {
+        "dense" => LayerKind::Dense { size: field()? },
+        "conv" => LayerKind::Conv {
+            channels: field()?,
+            kernel: field()?,
+            stride: field()?,
+            padding: field()?,
+        },
+        "max_pool" => LayerKind::MaxPool {
+            size: field()?,
+            stride: field()?,
+        },
+        "avg_pool" => LayerKind::AvgPool {
+            size: field()?,
+            stride: field()?,
+        },
+        "flatten" => LayerKind::Flatten,
+        "dropout" => LayerKind::Dropout { rate: r.f64()? },
+        "batch_norm" => LayerKind::BatchNorm,
+        "layer_norm" => LayerKind::LayerNorm,
+        _ => anyhow::bail!("unknown layer kind: {name:?}"),
+    })
+}
+
+pub fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.push(name.len() as u8);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < n {
+            anyhow::bail!("file is truncated");
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn f64(&mut self) -> anyhow::Result<f64> {
+        Ok(f64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn name(&mut self) -> anyhow::Result<String> {
+        let len = self.take(1)?[0] as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Init;
+    use rand::SeedableRng;
+
+    fn conf() -> NetConf {
+        let spec = |kind, activation: &str| LayerSpec {
+            kind,
+            activation: Activation::from_name(activation).unwrap(),
+        };
+        let layers = [
+            spec(
+                LayerKind::Conv {
+                    channels: 2,
+                    kernel: 3,
+                    stride: 1,
+                    padding: 1,
+                },
+                "relu",
+            ),
+            spec(LayerKind::MaxPool { size: 2, stride: 2 }, "identity"),
+            spec(LayerKind::Flatten, "identity"),
+            spec(LayerKind::Dense { size: 5 }, "identity"),
+            spec(LayerKind::BatchNorm, "tanh"),
+            spec(LayerKind::Dropout { rate: 0.25 }, "identity"),
+            spec(LayerKind::Dense { size: 3 }, "softmax"),
+        ];
+        let input = Shape {
+            channels: 1,
+            height: 4,
+            width: 4,
+        };
+        let layers = layers.map(|spec| (spec, Init::HeNormal));
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut conf = NetConf::new(input, &layers, &mut rng).unwrap();
+        let stats = conf.running_stats().len();
+        conf.load_running_stats((0..stats).map(|i| i as f64 * 0.5 + 0.25));
+        conf
+    }
+
+    /// `body` followed by its checksum, as [`encode`] ends a file.
+    fn with_checksum(mut body: Vec<u8>) -> Vec<u8> {
+        let checksum = crc32fast::hash(&body);
+        body.extend_from_slice(&checksum.to_be_bytes());
+        body
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match decode(bytes) {
+            Ok(_) => panic!("decoded an invalid model"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn body(bytes: &[u8]) -> Vec<u8> {
+        bytes[..bytes.len() - 4].to_vec()
+    }
+
+    #[test]
+    fn round_trips() {
+        let conf = conf();
+        let decoded = decode(&encode(&conf)).unwrap();
+        assert_eq!(decoded.input(), conf.input());
+        assert_eq!(decoded.specs(), conf.specs());
+        assert_eq!(decoded.cost().name, conf.cost().name);
+        assert_eq!(decoded.flatten(), conf.flatten());
+        assert_eq!(decoded.running_stats(), conf.running_stats());
+        assert_eq!(encode(&decoded), encode(&conf));
+    }
+
+    #[test]
+    fn rejects_bad_magic_and_version() {
+        let mut bytes = encode(&conf());
+        bytes[0] = b'X';
+        let err = error(&bytes);
+        assert_eq!(err, "not a model file (bad magic number)");
+
+        let mut bytes = body(&encode(&conf()));
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        let err = error(&with_checksum(bytes));
+        assert_eq!(
+            err,
+            format!(
+                "unsupported model version: {} (expected {VERSION})",
+                VERSION + 1
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_files() {
+        let bytes = encode(&conf());
+        for i in [MAGIC.len() + 4, bytes.len() / 2, bytes.len() - 5] {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            let err = error(&corrupted);
+            assert_eq!(
+                err,
+                "model checksum mismatch (file is truncated or corrupted)"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = encode(&conf());
+        for len in [2, MAGIC.len() + 4, bytes.len() / 2, bytes.len() - 1] {
+            error(&bytes[..len]);
+        }
+
+        // Missing parameters are caught even with a valid checksum.
+        let body = body(&bytes);
+        let err = error(&with_checksum(body[..body.len() - 8].to_vec()));
+        assert!(err.starts_with("model parameter data is"));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let bytes = encode(&conf());
+        let mut extra = bytes.clone();
+        extra.push(0);
+        error(&extra);
+
+        let mut body = body(&bytes);
+        body.extend_from_slice(&[0; 8]);
+        let err = error(&with_checksum(body));
+        assert!(err.starts_with("model parameter data is"));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::Init;
 use std::sync::{Arc, RwLock};
 
 pub type Matrix = nalgebra::base::DMatrix<f64>;
@@ -6,10 +7,19 @@ pub type Vector = nalgebra::base::DVector<f64>;
 pub struct Network {
     pub conf: Arc<RwLock<NetConf>>,
     pub state: NetState,
+    /// Whether dropout masks are applied. Off by default, so that the
+    /// network's output is deterministic.
+    pub training: bool,
+    /// Source of dropout masks in training.
+    pub rng: rand::rngs::StdRng,
+    /// Number of consecutive samples that batch normalization layers
+    /// normalize together in training, or all of a batch's if `None`.
+    pub norm_group: Option<usize>,
 }
 
 #[derive(Clone)]
 pub struct NetConf {
+    input: Shape,
     layers: Vec<Layer>,
     cost: Cost,
 }
@@ -17,65 +27,147 @@ pub struct NetConf {
 #[derive(Clone)]
 pub struct NetState {
     layers: Vec<Vector>,
+    pre: Vec<Vector>,
+    batch: Vec<Matrix>,
+    batch_pre: Vec<Matrix>,
+    /// Scaled dropout masks of each layer, for dropout layers in training.
+    masks: Vec<Option<Vector>>,
+    batch_training: Vec<Option<Training>>,
 }
 
+/// Values drawn or computed by a layer for a training batch, which
+/// backpropagation reuses.
+#[derive(Clone)]
+enum Training {
+    /// Scaled dropout mask.
+    Mask(Matrix),
+    /// Mean and variance of each of a batch normalization layer's inputs
+    /// over each group of `size` consecutive samples.
+    Stats {
+        size: usize,
+        groups: Vec<(Vector, Vector)>,
+    },
+}
+
+/// Dimensions of the values passed between layers. Values are stored as
+/// vectors, channel by channel, each channel in row-major order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Shape {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerKind {
+    Dense {
+        size: usize,
+    },
+    Conv {
+        channels: usize,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+    },
+    MaxPool {
+        size: usize,
+        stride: usize,
+    },
+    AvgPool {
+        size: usize,
+        stride: usize,
+    },
+    Flatten,
+    /// Zeroes each input with probability `rate` in training, scaling the
+    /// rest so that the expected output is unchanged.
+    Dropout {
+        rate: f64,
+    },
+    /// Normalizes each input over the batch in training, and with running
+    /// statistics otherwise, then applies a learned scale and shift.
+    BatchNorm,
+    /// Normalizes each sample over its inputs, then applies a learned
+    /// scale and shift.
+    LayerNorm,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LayerSpec {
+    pub kind: LayerKind,
+    pub activation: Activation,
+}
+
+/// Layers without parameters have empty `weights` and `biases`. For
+/// convolution layers, each row of `weights` is one output channel's kernel,
+/// and for normalization layers `weights` is a single column of scales.
 #[derive(Clone)]
 struct Layer {
+    kind: LayerKind,
+    input: Shape,
+    output: Shape,
     weights: Matrix,
     biases: Vector,
+    /// Running statistics of batch normalization layers, empty otherwise.
+    running_mean: Vector,
+    running_var: Vector,
     activation: Activation,
 }
 
+const NORM_EPSILON: f64 = 1.0e-5;
+/// Weight of each batch's statistics in the running statistics.
+const NORM_MOMENTUM: f64 = 0.1;
+
 impl Network {
-    pub fn new(layers: &[usize]) -> Self {
-        let conf = {
-            use rand::Rng;
-            let mut rng = rand::thread_rng();
-            let mut layers = layers
-                .windows(2)
-                .map(|layers| {
-                    let (input, output) = (layers[0], layers[1]);
-                    let weights = Matrix::from_fn(output, input, |_, _| {
-                        let mag = (2.0 / input as f64).sqrt();
-                        rng.gen_range(-mag..mag)
-                    });
-                    let biases = Vector::from_element(output, 0.0);
-                    Layer {
-                        weights,
-                        biases,
-                        activation: Activation::RELU,
-                    }
-                })
+    pub fn new(
+        input: Shape,
+        layers: &[(LayerSpec, Init)],
+        rng: &mut impl rand::Rng,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::from_conf(NetConf::new(input, layers, rng)?))
+    }
+
+    pub fn from_conf(conf: NetConf) -> Self {
+        let state = {
+            let layers = conf
+                .sizes()
+                .into_iter()
+                .map(|n| Vector::from_element(n, 0.0))
                 .collect::<Vec<_>>();
-            layers.last_mut().unwrap().activation = Activation::SOFTMAX;
-            NetConf {
+            NetState {
+                pre: layers[1..].to_vec(),
+                masks: vec![None; layers.len() - 1],
                 layers,
-                cost: Cost::CAT_CE,
+                batch: Vec::new(),
+                batch_pre: Vec::new(),
+                batch_training: Vec::new(),
             }
         };
-        let state = {
-            let layers = layers
-                .iter()
-                .copied()
-                .map(|n| Vector::from_element(n, 0.0))
-                .collect();
-            NetState { layers }
-        };
         let conf = Arc::new(RwLock::new(conf));
-        Self { conf, state }
+        Self {
+            conf,
+            state,
+            training: false,
+            rng: rand::SeedableRng::from_entropy(),
+            norm_group: None,
+        }
     }
 
     pub fn process(&mut self, input: &Vector) {
         self.state.layers[0].copy_from(input);
-        for (layer, (inp_i, out_i)) in self
-            .conf
-            .read()
-            .unwrap()
-            .layers
-            .iter()
-            .zip((0..self.state.layers.len()).zip(1..self.state.layers.len()))
-        {
-            self.state.layers[out_i] = layer.calculate(&self.state.layers[inp_i]);
+        for (i, layer) in self.conf.read().unwrap().layers.iter().enumerate() {
+            let mask = match (self.training, layer.kind) {
+                (true, LayerKind::Dropout { rate }) => Some(
+                    layer
+                        .dropout_mask(rate, 1, &mut self.rng)
+                        .column(0)
+                        .into_owned(),
+                ),
+                _ => None,
+            };
+            let (pre, out) = layer.calculate(&self.state.layers[i], mask.as_ref());
+            self.state.pre[i] = pre;
+            self.state.layers[i + 1] = out;
+            self.state.masks[i] = mask;
         }
     }
 
@@ -84,49 +176,252 @@ impl Network {
     }
 
     pub fn cost(&self, expected: &Vector) -> f64 {
-        (self.conf.read().unwrap().cost.fun)(self.output(), expected)
+        let conf = self.conf.read().unwrap();
+        if conf.fused_output() {
+            Cost::softmax_cat_ce(self.state.pre.last().unwrap(), expected)
+        } else {
+            (conf.cost.fun)(self.output(), expected)
+        }
     }
 
     pub fn gradient(&self, expected: &Vector) -> Vector {
         let conf = self.conf.read().unwrap();
-        let mut node_derivs = Vec::with_capacity(conf.layers.len());
-        node_derivs.push((conf.cost.deriv)(self.output(), expected));
-        let act_derivs = conf
+        // With softmax output and cross-entropy cost, the derivative with
+        // respect to the output layer's pre-activations is computed directly
+        // instead of through the cost derivative and softmax Jacobian.
+        let mut node_deriv =
+            (!conf.fused_output()).then(|| (conf.cost.deriv)(self.output(), expected));
+        let mut gradient = Vec::with_capacity(conf.param_count());
+        for ((((layer, pre), prev), out), mask) in conf
             .layers
             .iter()
+            .zip(self.state.pre.iter())
             .zip(self.state.layers.iter())
-            .map(|(layer, prev)| layer.deriv(prev))
+            .zip(self.state.layers[1..].iter())
+            .zip(self.state.masks.iter())
+            .rev()
+        {
+            let pre_deriv = match &node_deriv {
+                Some(node_deriv) => layer.activation.backward(pre, out, node_deriv),
+                None => Cost::softmax_cat_ce_deriv(self.output(), expected),
+            };
+            node_deriv = Some(layer.backward(prev, &pre_deriv, mask.as_ref(), &mut gradient));
+        }
+        gradient.into()
+    }
+
+    pub fn process_batch(&mut self, input: &Matrix) {
+        let conf = self.conf.read().unwrap();
+        self.state.batch.clear();
+        self.state.batch_pre.clear();
+        self.state.batch_training.clear();
+        self.state.batch.push(input.clone());
+        for layer in conf.layers.iter() {
+            let prev = self.state.batch.last().unwrap();
+            let training = self
+                .training
+                .then(|| layer.prepare(prev, self.norm_group, &mut self.rng))
+                .flatten();
+            let (pre, out) = layer.calculate_batch(prev, training.as_ref());
+            self.state.batch_pre.push(pre);
+            self.state.batch.push(out);
+            self.state.batch_training.push(training);
+        }
+    }
+
+    /// Sums of the inputs of each batch normalization layer and of their
+    /// squares over the last batch processed in training, in the order of
+    /// [`NetConf::running_stats`].
+    pub fn batch_stats(&self) -> Vector {
+        let data = self
+            .state
+            .batch_training
+            .iter()
+            .zip(self.state.batch.iter())
+            .flat_map(|(training, prev)| match training {
+                Some(Training::Stats { .. }) => {
+                    let sum = prev.column_sum();
+                    let squares = prev.component_mul(prev).column_sum();
+                    sum.iter().chain(squares.iter()).copied().collect()
+                }
+                _ => Vec::new(),
+            })
             .collect::<Vec<_>>();
-        for (i, (layer, act_deriv)) in conf.layers.iter().zip(act_derivs.iter()).rev().enumerate() {
-            // let next = &layer.weights * act_deriv * &node_derivs[i];
-            let next = layer.weights.transpose() * act_deriv * &node_derivs[i];
-            // let mut next = act_deriv * &node_derivs[i];
-            // next = &layer.weights * next;
-            node_derivs.push(next);
+        Vector::from_vec(data)
+    }
+
+    pub fn batch_output(&self) -> &Matrix {
+        self.state.batch.last().unwrap()
+    }
+
+    pub fn batch_cost(&self, expected: &Matrix) -> f64 {
+        let conf = self.conf.read().unwrap();
+        let columns = |m: &Matrix| {
+            m.column_iter()
+                .map(|col| col.into_owned())
+                .collect::<Vec<_>>()
+        };
+        if conf.fused_output() {
+            let logits = self.state.batch_pre.last().unwrap();
+            columns(logits)
+                .iter()
+                .zip(columns(expected).iter())
+                .map(|(logits, expected)| Cost::softmax_cat_ce(logits, expected))
+                .sum()
+        } else {
+            columns(self.batch_output())
+                .iter()
+                .zip(columns(expected).iter())
+                .map(|(actual, expected)| (conf.cost.fun)(actual, expected))
+                .sum()
         }
+    }
 
-        self.state
+    pub fn batch_gradient(&self, expected: &Matrix) -> Vector {
+        let conf = self.conf.read().unwrap();
+        let cost_derivs = |deriv: fn(&Vector, &Vector) -> Vector| {
+            Matrix::from_columns(
+                &self
+                    .batch_output()
+                    .column_iter()
+                    .zip(expected.column_iter())
+                    .map(|(actual, expected)| deriv(&actual.into_owned(), &expected.into_owned()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let mut node_derivs = (!conf.fused_output()).then(|| cost_derivs(conf.cost.deriv));
+
+        let mut gradient = Vec::with_capacity(conf.param_count());
+        for ((((layer, pre), prev), out), training) in conf
             .layers
             .iter()
-            .zip(act_derivs.iter())
+            .zip(self.state.batch_pre.iter())
+            .zip(self.state.batch.iter())
+            .zip(self.state.batch[1..].iter())
+            .zip(self.state.batch_training.iter())
             .rev()
-            .zip(node_derivs.iter())
-            .flat_map(|((prev, act_deriv), layer_deriv)| {
-                let bias_deriv = act_deriv * layer_deriv;
-                let weight_deriv = &bias_deriv * prev.transpose();
-                weight_deriv
-                    .iter()
-                    .copied()
-                    .chain(bias_deriv.iter().copied())
-                    .collect::<Vec<_>>()
-                    .into_iter()
-            })
-            .collect::<Vec<_>>()
-            .into()
+        {
+            let pre_derivs = match &node_derivs {
+                Some(node_derivs) => layer.activation.backward_batch(pre, out, node_derivs),
+                None => cost_derivs(Cost::softmax_cat_ce_deriv),
+            };
+            node_derivs =
+                Some(layer.backward_batch(prev, &pre_derivs, training.as_ref(), &mut gradient));
+        }
+        gradient.into()
     }
 }
 
 impl NetConf {
+    /// A network with weights drawn from `rng` by each layer's initializer,
+    /// which is ignored for layers without weights.
+    pub fn new(
+        input: Shape,
+        layers: &[(LayerSpec, Init)],
+        rng: &mut impl rand::Rng,
+    ) -> anyhow::Result<Self> {
+        let specs = layers.iter().map(|(spec, _)| *spec).collect::<Vec<_>>();
+        let mut conf = Self::zeroed(input, &specs, Cost::CAT_CE)?;
+        for (layer, (_, init)) in conf.layers.iter_mut().zip(layers) {
+            if layer.is_norm() {
+                layer.weights.fill(1.0);
+                continue;
+            }
+            let fan_in = layer.weights.ncols();
+            let fan_out = match layer.kind {
+                LayerKind::Conv { kernel, .. } => layer.weights.nrows() * kernel.pow(2),
+                _ => layer.weights.nrows(),
+            };
+            init.fill(&mut layer.weights, fan_in, fan_out, rng);
+        }
+        Ok(conf)
+    }
+
+    pub fn zeroed(input: Shape, specs: &[LayerSpec], cost: Cost) -> anyhow::Result<Self> {
+        let mut shape = input;
+        let layers = specs
+            .iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let layer = Layer::zeroed(shape, *spec)
+                    .map_err(|e| anyhow::anyhow!("layer {}: {e}", i + 1))?;
+                shape = layer.output;
+                Ok(layer)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            input,
+            layers,
+            cost,
+        })
+    }
+
+    pub fn input(&self) -> Shape {
+        self.input
+    }
+
+    /// Lengths of the input and of each layer's output.
+    pub fn sizes(&self) -> Vec<usize> {
+        std::iter::once(self.input.len())
+            .chain(self.layers.iter().map(|layer| layer.output.len()))
+            .collect()
+    }
+
+    /// Shapes of the input and of each layer's output.
+    pub fn shapes(&self) -> Vec<Shape> {
+        std::iter::once(self.input)
+            .chain(self.layers.iter().map(|layer| layer.output))
+            .collect()
+    }
+
+    pub fn specs(&self) -> Vec<LayerSpec> {
+        self.layers
+            .iter()
+            .map(|layer| LayerSpec {
+                kind: layer.kind,
+                activation: layer.activation,
+            })
+            .collect()
+    }
+
+    pub fn cost(&self) -> Cost {
+        self.cost
+    }
+
+    /// Whether the output layer is softmax paired with cross-entropy cost,
+    /// in which case the two are computed together for numerical stability.
+    fn fused_output(&self) -> bool {
+        self.layers.last().unwrap().activation.name == Activation::SOFTMAX.name
+            && self.cost.name == Cost::CAT_CE.name
+    }
+
+    pub fn layer_params(&self) -> impl Iterator<Item = (&Matrix, &Vector)> {
+        self.layers
+            .iter()
+            .map(|layer| (&layer.weights, &layer.biases))
+    }
+
+    pub fn param_count(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.weights.len() + layer.biases.len())
+            .sum()
+    }
+
+    pub fn weight_mask(&self) -> Vector {
+        let data = self
+            .layers
+            .iter()
+            .rev()
+            .flat_map(|layer| {
+                let decay = if layer.is_norm() { 0.0 } else { 1.0 };
+                std::iter::repeat_n(decay, layer.weights.len())
+                    .chain(std::iter::repeat_n(0.0, layer.biases.len()))
+            })
+            .collect::<Vec<_>>();
+        Vector::from_column_slice(&data)
+    }
+
     pub fn flatten(&self) -> Vector {
         let data = self
             .layers
@@ -151,72 +446,839 @@ impl NetConf {
             .zip(iter)
             .for_each(|(n, val)| *n = val);
     }
+
+    /// Whether all parameters and running statistics are finite.
+    pub fn is_finite(&self) -> bool {
+        self.flatten()
+            .iter()
+            .chain(self.running_stats().iter())
+            .all(|n| n.is_finite())
+    }
+
+    /// Scales down the incoming weights of each dense neuron and the kernels
+    /// of each convolution channel whose norm exceeds `max`.
+    pub fn clip_max_norm(&mut self, max: f64) {
+        self.layers
+            .iter_mut()
+            .filter(|layer| matches!(layer.kind, LayerKind::Dense { .. } | LayerKind::Conv { .. }))
+            .flat_map(|layer| layer.weights.row_iter_mut())
+            .for_each(|mut row| {
+                let norm = row.norm();
+                if norm > max {
+                    row *= max / norm;
+                }
+            });
+    }
+
+    /// Running means and variances of the batch normalization layers, in
+    /// layer order. These are not trained by the optimizer.
+    pub fn running_stats(&self) -> Vector {
+        let data = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.running_mean.iter().chain(layer.running_var.iter()))
+            .copied()
+            .collect::<Vec<_>>();
+        Vector::from_vec(data)
+    }
+
+    pub fn load_running_stats(&mut self, iter: impl Iterator<Item = f64>) {
+        self.layers
+            .iter_mut()
+            .flat_map(|layer| {
+                layer
+                    .running_mean
+                    .iter_mut()
+                    .chain(layer.running_var.iter_mut())
+            })
+            .zip(iter)
+            .for_each(|(n, val)| *n = val);
+    }
+
+    /// Moves the running statistics towards the mean and variance over a
+    /// batch of `count` samples, given the sums of [`Network::batch_stats`]
+    /// over the whole batch.
+    pub fn update_running_stats(&mut self, sums: &Vector, count: usize) {
+        let mut sums = sums.iter().map(|n| n / count as f64);
+        let stats = self
+            .layers
+            .iter()
+            .flat_map(|layer| {
+                let len = layer.running_mean.len();
+                let mean = Vector::from_iterator(len, sums.by_ref().take(len));
+                let squares = Vector::from_iterator(len, sums.by_ref().take(len));
+                let var = (squares - mean.component_mul(&mean)).map(|n| n.max(0.0));
+                mean.iter().chain(var.iter()).copied().collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let updated =
+            self.running_stats() * (1.0 - NORM_MOMENTUM) + Vector::from_vec(stats) * NORM_MOMENTUM;
+        self.load_running_stats(updated.iter().copied());
+    }
+}
+
+impl Shape {
+    pub fn flat(len: usize) -> Self {
+        Self {
+            channels: len,
+            height: 1,
+            width: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels * self.height * self.width
+    }
+
+    fn index(&self, channel: usize, y: usize, x: usize) -> usize {
+        (channel * self.height + y) * self.width + x
+    }
+}
+
+impl std::fmt::Display for Shape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}x{}", self.channels, self.height, self.width)
+    }
+}
+
+impl LayerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dense { .. } => "dense",
+            Self::Conv { .. } => "conv",
+            Self::MaxPool { .. } => "max_pool",
+            Self::AvgPool { .. } => "avg_pool",
+            Self::Flatten => "flatten",
+            Self::Dropout { .. } => "dropout",
+            Self::BatchNorm => "batch_norm",
+            Self::LayerNorm => "layer_norm",
+        }
+    }
+}
+
+impl std::fmt::Display for LayerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())?;
+        match self {
+            Self::Dense { size } => write!(f, " {size}"),
+            Self::Conv {
+                channels,
+                kernel,
+                stride,
+                padding,
+            } => write!(
+                f,
+                " {channels} (kernel {kernel}, stride {stride}, padding {padding})"
+            ),
+            Self::MaxPool { size, stride } | Self::AvgPool { size, stride } => {
+                write!(f, " {size} (stride {stride})")
+            }
+            Self::Flatten | Self::BatchNorm | Self::LayerNorm => Ok(()),
+            Self::Dropout { rate } => write!(f, " {rate}"),
+        }
+    }
+}
+
+impl std::fmt::Display for LayerSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.kind, self.activation.name)
+    }
+}
+
+/// Normalizes each row of `x` with the given mean and variance.
+fn normalize_rows(x: &Matrix, mean: &Vector, var: &Vector) -> Matrix {
+    Matrix::from_fn(x.nrows(), x.ncols(), |i, j| {
+        (x[(i, j)] - mean[i]) / (var[i] + NORM_EPSILON).sqrt()
+    })
+}
+
+/// Derivative with respect to `x` of [`normalize_rows`] with each row's own
+/// mean and variance `var` over the columns, given the normalized values and
+/// the derivative with respect to them.
+fn normalize_rows_backward(normalized: &Matrix, var: &Vector, deriv: &Matrix) -> Matrix {
+    let mean = deriv.column_mean();
+    let projection = deriv.component_mul(normalized).column_mean();
+    Matrix::from_fn(deriv.nrows(), deriv.ncols(), |i, j| {
+        (deriv[(i, j)] - mean[i] - normalized[(i, j)] * projection[i])
+            / (var[i] + NORM_EPSILON).sqrt()
+    })
+}
+
+/// Starts and lengths of consecutive groups of at most `size` of `count`
+/// columns.
+fn groups(count: usize, size: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..count)
+        .step_by(size)
+        .map(move |start| (start, size.min(count - start)))
+}
+
+/// `x` normalized with its own mean and variance, and the standard deviation
+/// it was divided by.
+fn layer_norm(x: &Vector) -> (Vector, f64) {
+    let std = (x.variance() + NORM_EPSILON).sqrt();
+    (x.add_scalar(-x.mean()) / std, std)
+}
+
+/// Number of positions a window of `size` fits at along a side of `len`.
+fn window_count(len: usize, size: usize, stride: usize) -> anyhow::Result<usize> {
+    if size == 0 || stride == 0 {
+        anyhow::bail!("window size and stride must be positive");
+    }
+    if size > len {
+        anyhow::bail!("window size {size} is larger than the input ({len})");
+    }
+    Ok((len - size) / stride + 1)
 }
 
 impl Layer {
-    fn calculate(&self, prev: &Vector) -> Vector {
-        (self.activation.fun)(&self.weights * prev + &self.biases)
+    fn zeroed(input: Shape, spec: LayerSpec) -> anyhow::Result<Self> {
+        let (output, weights, biases) = match spec.kind {
+            LayerKind::Dense { size } => (
+                Shape::flat(size),
+                Matrix::zeros(size, input.len()),
+                Vector::zeros(size),
+            ),
+            LayerKind::Conv {
+                channels,
+                kernel,
+                stride,
+                padding,
+            } => {
+                let output = Shape {
+                    channels,
+                    height: window_count(input.height + 2 * padding, kernel, stride)?,
+                    width: window_count(input.width + 2 * padding, kernel, stride)?,
+                };
+                let weights = Matrix::zeros(channels, input.channels * kernel.pow(2));
+                (output, weights, Vector::zeros(channels))
+            }
+            LayerKind::MaxPool { size, stride } | LayerKind::AvgPool { size, stride } => {
+                let output = Shape {
+                    channels: input.channels,
+                    height: window_count(input.height, size, stride)?,
+                    width: window_count(input.width, size, stride)?,
+                };
+                (output, Matrix::zeros(0, 0), Vector::zeros(0))
+            }
+            LayerKind::Flatten => (
+                Shape::flat(input.len()),
+                Matrix::zeros(0, 0),
+                Vector::zeros(0),
+            ),
+            LayerKind::Dropout { rate } => {
+                if !(0.0..1.0).contains(&rate) {
+                    anyhow::bail!("dropout rate must be at least 0 and less than 1, not {rate}");
+                }
+                (input, Matrix::zeros(0, 0), Vector::zeros(0))
+            }
+            LayerKind::BatchNorm | LayerKind::LayerNorm => (
+                input,
+                Matrix::zeros(input.len(), 1),
+                Vector::zeros(input.len()),
+            ),
+        };
+        let stats = if spec.kind == LayerKind::BatchNorm {
+            input.len()
+        } else {
+            0
+        };
+        Ok(Self {
+            kind: spec.kind,
+            input,
+            output,
+            weights,
+            biases,
+            running_mean: Vector::zeros(stats),
+            running_var: Vector::from_element(stats, 1.0),
+            activation: spec.activation,
+        })
+    }
+
+    fn is_norm(&self) -> bool {
+        matches!(self.kind, LayerKind::BatchNorm | LayerKind::LayerNorm)
+    }
+
+    /// Values to use for `prev` in training, for layers that need them.
+    /// Batch normalization statistics are computed over each group of
+    /// `norm_group` samples, or all of them if `None`.
+    fn prepare(
+        &self,
+        prev: &Matrix,
+        norm_group: Option<usize>,
+        rng: &mut impl rand::Rng,
+    ) -> Option<Training> {
+        match self.kind {
+            LayerKind::Dropout { rate } => {
+                Some(Training::Mask(self.dropout_mask(rate, prev.ncols(), rng)))
+            }
+            LayerKind::BatchNorm => {
+                let size = norm_group.unwrap_or(prev.ncols());
+                let groups = groups(prev.ncols(), size)
+                    .map(|(start, len)| {
+                        let group = prev.columns(start, len);
+                        (group.column_mean(), group.column_variance())
+                    })
+                    .collect();
+                Some(Training::Stats { size, groups })
+            }
+            _ => None,
+        }
+    }
+
+    /// Random dropout mask for `count` samples, with the kept inputs
+    /// scaled by `1 / (1 - rate)`.
+    fn dropout_mask(&self, rate: f64, count: usize, rng: &mut impl rand::Rng) -> Matrix {
+        let scale = 1.0 / (1.0 - rate);
+        Matrix::from_fn(self.input.len(), count, |_, _| {
+            if rng.gen::<f64>() < rate {
+                0.0
+            } else {
+                scale
+            }
+        })
+    }
+
+    fn calculate(&self, prev: &Vector, mask: Option<&Vector>) -> (Vector, Vector) {
+        let pre = match mask {
+            Some(mask) => prev.component_mul(mask),
+            None => self.forward(prev),
+        };
+        let out = self.activation.apply(pre.clone());
+        (pre, out)
+    }
+
+    fn calculate_batch(&self, prev: &Matrix, training: Option<&Training>) -> (Matrix, Matrix) {
+        let pre = match (self.kind, training) {
+            (_, Some(Training::Mask(mask))) => prev.component_mul(mask),
+            (
+                _,
+                Some(Training::Stats {
+                    size,
+                    groups: stats,
+                }),
+            ) => {
+                let mut pre = prev.clone();
+                for ((start, len), (mean, var)) in groups(prev.ncols(), *size).zip(stats) {
+                    let group = self.batch_norm(&prev.columns(start, len).into_owned(), mean, var);
+                    pre.columns_mut(start, len).copy_from(&group);
+                }
+                pre
+            }
+            (LayerKind::Dense { .. }, None) => {
+                let mut pre = &self.weights * prev;
+                pre.column_iter_mut()
+                    .for_each(|mut col| col += &self.biases);
+                pre
+            }
+            (LayerKind::BatchNorm, None) => {
+                self.batch_norm(prev, &self.running_mean, &self.running_var)
+            }
+            (LayerKind::Flatten | LayerKind::Dropout { .. }, None) => prev.clone(),
+            _ => Matrix::from_columns(
+                &prev
+                    .column_iter()
+                    .map(|col| self.forward(&col.into_owned()))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let out = self.activation.apply_batch(pre.clone());
+        (pre, out)
+    }
+
+    /// Pre-activation values of the layer for the input `prev`.
+    fn forward(&self, prev: &Vector) -> Vector {
+        match self.kind {
+            LayerKind::Dense { .. } => &self.weights * prev + &self.biases,
+            LayerKind::Conv { .. } => {
+                let mut pre = self.patches(prev) * self.weights.transpose();
+                pre.column_iter_mut()
+                    .zip(self.biases.iter())
+                    .for_each(|(mut col, bias)| col.add_scalar_mut(*bias));
+                Vector::from_column_slice(pre.as_slice())
+            }
+            LayerKind::MaxPool { .. } | LayerKind::AvgPool { .. } => {
+                let mut pre = Vector::zeros(self.output.len());
+                self.for_each_window(|out, window| {
+                    pre[out] = match self.kind {
+                        LayerKind::MaxPool { .. } => {
+                            window.map(|i| prev[i]).fold(f64::NEG_INFINITY, f64::max)
+                        }
+                        _ => window.map(|i| prev[i]).sum::<f64>() / self.pool_area(),
+                    }
+                });
+                pre
+            }
+            LayerKind::Flatten | LayerKind::Dropout { .. } => prev.clone(),
+            LayerKind::BatchNorm => {
+                let prev = Matrix::from_column_slice(prev.nrows(), 1, prev.as_slice());
+                let pre = self.batch_norm(&prev, &self.running_mean, &self.running_var);
+                Vector::from_column_slice(pre.as_slice())
+            }
+            LayerKind::LayerNorm => {
+                let (normalized, _) = layer_norm(prev);
+                normalized.component_mul(&self.weights.column(0)) + &self.biases
+            }
+        }
+    }
+
+    /// Batch normalization of `prev` with the given statistics.
+    fn batch_norm(&self, prev: &Matrix, mean: &Vector, var: &Vector) -> Matrix {
+        let mut pre = normalize_rows(prev, mean, var);
+        pre.row_iter_mut()
+            .zip(self.weights.iter().zip(self.biases.iter()))
+            .for_each(|(mut row, (scale, shift))| {
+                row *= *scale;
+                row.add_scalar_mut(*shift);
+            });
+        pre
+    }
+
+    /// Appends the gradient of the layer's parameters to `gradient`, given
+    /// the input `prev`, the derivative with respect to the layer's
+    /// pre-activations and the dropout mask used for them, and returns the
+    /// derivative with respect to `prev`.
+    fn backward(
+        &self,
+        prev: &Vector,
+        pre_deriv: &Vector,
+        mask: Option<&Vector>,
+        gradient: &mut Vec<f64>,
+    ) -> Vector {
+        if let Some(mask) = mask {
+            return pre_deriv.component_mul(mask);
+        }
+        match self.kind {
+            LayerKind::Dense { .. } => {
+                let weight_deriv = pre_deriv * prev.transpose();
+                gradient.extend(weight_deriv.iter().copied());
+                gradient.extend(pre_deriv.iter().copied());
+                self.weights.tr_mul(pre_deriv)
+            }
+            LayerKind::Conv { .. } => {
+                let positions = self.output.height * self.output.width;
+                let pre_deriv = Matrix::from_column_slice(
+                    positions,
+                    self.output.channels,
+                    pre_deriv.as_slice(),
+                );
+                let weight_deriv = pre_deriv.tr_mul(&self.patches(prev));
+                gradient.extend(weight_deriv.iter().copied());
+                gradient.extend(pre_deriv.row_sum_tr().iter().copied());
+                self.unpatch(&(pre_deriv * &self.weights))
+            }
+            LayerKind::MaxPool { .. } | LayerKind::AvgPool { .. } => {
+                let mut prev_deriv = Vector::zeros(self.input.len());
+                self.for_each_window(|out, window| match self.kind {
+                    LayerKind::MaxPool { .. } => {
+                        let first = window.next().unwrap();
+                        let max =
+                            window.fold(first, |max, i| if prev[i] > prev[max] { i } else { max });
+                        prev_deriv[max] += pre_deriv[out];
+                    }
+                    _ => window.for_each(|i| prev_deriv[i] += pre_deriv[out] / self.pool_area()),
+                });
+                prev_deriv
+            }
+            LayerKind::Flatten | LayerKind::Dropout { .. } => pre_deriv.clone(),
+            LayerKind::BatchNorm => {
+                let prev = Matrix::from_column_slice(prev.nrows(), 1, prev.as_slice());
+                let normalized = normalize_rows(&prev, &self.running_mean, &self.running_var);
+                gradient.extend(
+                    pre_deriv
+                        .component_mul(&normalized.column(0))
+                        .iter()
+                        .copied(),
+                );
+                gradient.extend(pre_deriv.iter().copied());
+                pre_deriv.zip_zip_map(
+                    &self.weights.column(0),
+                    &self.running_var,
+                    |n, scale, var| n * scale / (var + NORM_EPSILON).sqrt(),
+                )
+            }
+            LayerKind::LayerNorm => {
+                let (normalized, std) = layer_norm(prev);
+                gradient.extend(pre_deriv.component_mul(&normalized).iter().copied());
+                gradient.extend(pre_deriv.iter().copied());
+                let deriv = pre_deriv.component_mul(&self.weights.column(0));
+                let projection = deriv.dot(&normalized) / deriv.len() as f64;
+                (deriv.add_scalar(-deriv.mean()) - normalized * projection) / std
+            }
+        }
+    }
+
+    fn backward_batch(
+        &self,
+        prev: &Matrix,
+        pre_derivs: &Matrix,
+        training: Option<&Training>,
+        gradient: &mut Vec<f64>,
+    ) -> Matrix {
+        match training {
+            Some(Training::Mask(mask)) => return pre_derivs.component_mul(mask),
+            Some(Training::Stats {
+                size,
+                groups: stats,
+            }) => {
+                let mut scale_deriv = Vector::zeros(self.weights.len());
+                let mut prev_derivs = Matrix::zeros(prev.nrows(), prev.ncols());
+                for ((start, len), (mean, var)) in groups(prev.ncols(), *size).zip(stats) {
+                    let normalized =
+                        normalize_rows(&prev.columns(start, len).into_owned(), mean, var);
+                    let mut derivs = pre_derivs.columns(start, len).into_owned();
+                    scale_deriv += derivs.component_mul(&normalized).column_sum();
+                    derivs
+                        .row_iter_mut()
+                        .zip(self.weights.iter())
+                        .for_each(|(mut row, scale)| row *= *scale);
+                    prev_derivs
+                        .columns_mut(start, len)
+                        .copy_from(&normalize_rows_backward(&normalized, var, &derivs));
+                }
+                gradient.extend(scale_deriv.iter().copied());
+                gradient.extend(pre_derivs.column_sum().iter().copied());
+                return prev_derivs;
+            }
+            None => {}
+        }
+        match self.kind {
+            LayerKind::Dense { .. } => {
+                let weight_deriv = pre_derivs * prev.transpose();
+                gradient.extend(weight_deriv.iter().copied());
+                gradient.extend(pre_derivs.column_sum().iter().copied());
+                self.weights.tr_mul(pre_derivs)
+            }
+            LayerKind::Flatten | LayerKind::Dropout { .. } => pre_derivs.clone(),
+            _ => {
+                // Sum the parameter gradients of each sample.
+                let mut params = Vector::zeros(self.weights.len() + self.biases.len());
+                let prev_derivs = prev
+                    .column_iter()
+                    .zip(pre_derivs.column_iter())
+                    .map(|(prev, pre_deriv)| {
+                        let mut sample = Vec::with_capacity(params.len());
+                        let prev_deriv = self.backward(
+                            &prev.into_owned(),
+                            &pre_deriv.into_owned(),
+                            None,
+                            &mut sample,
+                        );
+                        params += Vector::from_vec(sample);
+                        prev_deriv
+                    })
+                    .collect::<Vec<_>>();
+                gradient.extend(params.iter().copied());
+                Matrix::from_columns(&prev_derivs)
+            }
+        }
+    }
+
+    /// Convolution input rearranged so that each row is the (zero padded)
+    /// patch of `prev` that one output position's kernels are applied to.
+    fn patches(&self, prev: &Vector) -> Matrix {
+        let mut patches =
+            Matrix::zeros(self.output.height * self.output.width, self.weights.ncols());
+        self.for_each_patch(|row, col, i| patches[(row, col)] = prev[i]);
+        patches
+    }
+
+    /// Inverse of [`Self::patches`], summing the entries that came from
+    /// the same input value.
+    fn unpatch(&self, patches: &Matrix) -> Vector {
+        let mut prev = Vector::zeros(self.input.len());
+        self.for_each_patch(|row, col, i| prev[i] += patches[(row, col)]);
+        prev
+    }
+
+    /// Calls `f(row, col, i)` for every entry of the patch matrix that
+    /// corresponds to input value `i` rather than padding.
+    fn for_each_patch(&self, mut f: impl FnMut(usize, usize, usize)) {
+        let LayerKind::Conv {
+            kernel,
+            stride,
+            padding,
+            ..
+        } = self.kind
+        else {
+            unreachable!()
+        };
+        for y in 0..self.output.height {
+            for x in 0..self.output.width {
+                let row = y * self.output.width + x;
+                for channel in 0..self.input.channels {
+                    for ky in 0..kernel {
+                        for kx in 0..kernel {
+                            let col = (channel * kernel + ky) * kernel + kx;
+                            let (Some(iy), Some(ix)) = (
+                                (y * stride + ky).checked_sub(padding),
+                                (x * stride + kx).checked_sub(padding),
+                            ) else {
+                                continue;
+                            };
+                            if iy < self.input.height && ix < self.input.width {
+                                f(row, col, self.input.index(channel, iy, ix));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `f(out, window)` for every pooling output, where `window`
+    /// iterates over the indices of the inputs it covers.
+    fn for_each_window(&self, mut f: impl FnMut(usize, &mut dyn Iterator<Item = usize>)) {
+        let (LayerKind::MaxPool { size, stride } | LayerKind::AvgPool { size, stride }) = self.kind
+        else {
+            unreachable!()
+        };
+        let input = self.input;
+        for channel in 0..self.output.channels {
+            for y in 0..self.output.height {
+                for x in 0..self.output.width {
+                    let mut window = (0..size).flat_map(|ky| {
+                        (0..size)
+                            .map(move |kx| input.index(channel, y * stride + ky, x * stride + kx))
+                    });
+                    f(self.output.index(channel, y, x), &mut window);
+                }
+            }
+        }
     }
 
-    fn deriv(&self, prev: &Vector) -> Matrix {
-        (self.activation.deriv)(&(&self.weights * prev + &self.biases))
+    fn pool_area(&self) -> f64 {
+        match self.kind {
+            LayerKind::MaxPool { size, .. } | LayerKind::AvgPool { size, .. } => size.pow(2) as f64,
+            _ => unreachable!(),
+        }
     }
 }
 
-use funcs::*;
+pub use funcs::{Activation, Cost};
 mod funcs {
     use super::*;
 
     #[derive(Clone, Copy)]
     pub struct Activation {
-        pub fun: fn(Vector) -> Vector,
-        pub deriv: fn(&Vector) -> Matrix,
+        pub name: &'static str,
+        pub kind: ActivationKind,
+    }
+
+    #[derive(Clone, Copy)]
+    pub enum ActivationKind {
+        /// Each output depends only on the corresponding input, so the
+        /// Jacobian is diagonal and `deriv` gives its entries from the
+        /// pre-activation values.
+        Elementwise {
+            fun: fn(f64) -> f64,
+            deriv: fn(f64) -> f64,
+        },
+        /// Each output depends on every input. `vjp` gives the product of
+        /// the transposed Jacobian with a vector, from the layer's output.
+        Vector {
+            fun: fn(Vector) -> Vector,
+            vjp: fn(&Vector, &Vector) -> Vector,
+        },
+    }
+
+    impl PartialEq for Activation {
+        fn eq(&self, other: &Self) -> bool {
+            self.name == other.name
+        }
+    }
+
+    impl std::fmt::Debug for Activation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.name)
+        }
     }
 
     impl Activation {
-        pub const RELU: Self = Self {
-            fun: Self::relu,
-            deriv: Self::relu_deriv,
-        };
+        pub const RELU: Self = Self::elementwise("relu", Self::relu, Self::relu_deriv);
+        pub const LEAKY_RELU: Self =
+            Self::elementwise("leaky_relu", Self::leaky_relu, Self::leaky_relu_deriv);
+        pub const ELU: Self = Self::elementwise("elu", Self::elu, Self::elu_deriv);
+        pub const SIGMOID: Self = Self::elementwise("sigmoid", sigmoid, Self::sigmoid_deriv);
+        pub const TANH: Self = Self::elementwise("tanh", f64::tanh, Self::tanh_deriv);
+        pub const GELU: Self = Self::elementwise("gelu", Self::gelu, Self::gelu_deriv);
+        pub const SOFTPLUS: Self = Self::elementwise("softplus", Self::softplus, sigmoid);
+        pub const IDENTITY: Self = Self::elementwise("identity", |n| n, |_| 1.0);
         pub const SOFTMAX: Self = Self {
-            fun: Self::softmax,
-            deriv: Self::softmax_deriv,
+            name: "softmax",
+            kind: ActivationKind::Vector {
+                fun: Self::softmax,
+                vjp: Self::softmax_vjp,
+            },
         };
+        pub const ALL: &'static [Self] = &[
+            Self::RELU,
+            Self::LEAKY_RELU,
+            Self::ELU,
+            Self::SIGMOID,
+            Self::TANH,
+            Self::GELU,
+            Self::SOFTPLUS,
+            Self::IDENTITY,
+            Self::SOFTMAX,
+        ];
+        const LEAKY_SLOPE: f64 = 0.01;
+        /// sqrt(2 / pi), for the tanh approximation of GELU.
+        const GELU_SCALE: f64 = 0.7978845608028654;
+        const GELU_CUBIC: f64 = 0.044715;
 
-        fn relu(mut input: Vector) -> Vector {
-            input.apply(|n| *n = n.max(0.0));
-            input
+        const fn elementwise(
+            name: &'static str,
+            fun: fn(f64) -> f64,
+            deriv: fn(f64) -> f64,
+        ) -> Self {
+            Self {
+                name,
+                kind: ActivationKind::Elementwise { fun, deriv },
+            }
         }
-        fn relu_deriv(input: &Vector) -> Matrix {
-            let mut output = Matrix::from_element(input.nrows(), input.nrows(), 0.0);
-            input
-                .iter()
-                .enumerate()
-                .for_each(|(i, n)| output[(i, i)] = (*n >= 0.0) as u64 as f64);
-            output
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            Self::ALL.iter().copied().find(|act| act.name == name)
+        }
+
+        pub fn apply(&self, mut input: Vector) -> Vector {
+            match self.kind {
+                ActivationKind::Elementwise { fun, .. } => {
+                    input.apply(|n| *n = fun(*n));
+                    input
+                }
+                ActivationKind::Vector { fun, .. } => fun(input),
+            }
+        }
+
+        pub fn apply_batch(&self, mut input: Matrix) -> Matrix {
+            match self.kind {
+                ActivationKind::Elementwise { fun, .. } => {
+                    input.apply(|n| *n = fun(*n));
+                    input
+                }
+                ActivationKind::Vector { fun, .. } => Matrix::from_columns(
+                    &input
+                        .column_iter()
+                        .map(|col| fun(col.into_owned()))
+                        .collect::<Vec<_>>(),
+                ),
+            }
+        }
+
+        /// Derivative of the cost with respect to the pre-activation values
+        /// `pre`, given the activation's output `out` and the derivative of
+        /// the cost with respect to it.
+        pub fn backward(&self, pre: &Vector, out: &Vector, node_deriv: &Vector) -> Vector {
+            match self.kind {
+                ActivationKind::Elementwise { deriv, .. } => {
+                    node_deriv.zip_map(pre, |node_deriv, pre| node_deriv * deriv(pre))
+                }
+                ActivationKind::Vector { vjp, .. } => vjp(out, node_deriv),
+            }
+        }
+
+        pub fn backward_batch(&self, pre: &Matrix, out: &Matrix, node_derivs: &Matrix) -> Matrix {
+            match self.kind {
+                ActivationKind::Elementwise { deriv, .. } => {
+                    node_derivs.zip_map(pre, |node_deriv, pre| node_deriv * deriv(pre))
+                }
+                ActivationKind::Vector { vjp, .. } => Matrix::from_columns(
+                    &out.column_iter()
+                        .zip(node_derivs.column_iter())
+                        .map(|(out, node_deriv)| vjp(&out.into_owned(), &node_deriv.into_owned()))
+                        .collect::<Vec<_>>(),
+                ),
+            }
+        }
+
+        fn relu(n: f64) -> f64 {
+            n.max(0.0)
+        }
+        fn relu_deriv(n: f64) -> f64 {
+            (n >= 0.0) as u64 as f64
+        }
+
+        fn leaky_relu(n: f64) -> f64 {
+            if n > 0.0 {
+                n
+            } else {
+                Self::LEAKY_SLOPE * n
+            }
+        }
+        fn leaky_relu_deriv(n: f64) -> f64 {
+            if n > 0.0 {
+                1.0
+            } else {
+                Self::LEAKY_SLOPE
+            }
+        }
+
+        fn elu(n: f64) -> f64 {
+            if n > 0.0 {
+                n
+            } else {
+                n.exp_m1()
+            }
+        }
+        fn elu_deriv(n: f64) -> f64 {
+            if n > 0.0 {
+                1.0
+            } else {
+                n.exp()
+            }
+        }
+
+        fn sigmoid_deriv(n: f64) -> f64 {
+            sigmoid(n) * (1.0 - sigmoid(n))
+        }
+
+        fn tanh_deriv(n: f64) -> f64 {
+            1.0 - n.tanh().powi(2)
+        }
+
+        fn gelu(n: f64) -> f64 {
+            let inner = Self::GELU_SCALE * (n + Self::GELU_CUBIC * n.powi(3));
+            0.5 * n * (1.0 + inner.tanh())
+        }
+        fn gelu_deriv(n: f64) -> f64 {
+            let inner = Self::GELU_SCALE * (n + Self::GELU_CUBIC * n.powi(3));
+            let t = inner.tanh();
+            let inner_deriv = Self::GELU_SCALE * (1.0 + 3.0 * Self::GELU_CUBIC * n.powi(2));
+            0.5 * (1.0 + t) + 0.5 * n * (1.0 - t.powi(2)) * inner_deriv
+        }
+
+        fn softplus(n: f64) -> f64 {
+            // Rearranged from ln(1 + e^n) to avoid overflow for large n.
+            n.max(0.0) + (-n.abs()).exp().ln_1p()
         }
 
         fn softmax(mut input: Vector) -> Vector {
-            input.apply(|n| *n = n.exp());
+            let max = input.max();
+            input.apply(|n| *n = (*n - max).exp());
             let sum = input.sum();
             input.apply(|n| *n /= sum);
             input
         }
-        fn softmax_deriv(input: &Vector) -> Matrix {
-            let mut output = Matrix::from_element(input.nrows(), input.nrows(), 0.0);
-            input
-                .iter()
-                .enumerate()
-                .for_each(|(i, n)| output[(i, i)] = n.exp());
-            let sum = output.sum();
-            output.map_with_location(|r, c, n| {
-                (n * sum - output[(r, r)] * output[(c, c)]) / sum.powi(2)
-            })
+        fn softmax_vjp(out: &Vector, node_deriv: &Vector) -> Vector {
+            let dot = out.dot(node_deriv);
+            out.zip_map(node_deriv, |out, node_deriv| out * (node_deriv - dot))
+        }
+
+        pub fn log_softmax(input: &Vector) -> Vector {
+            let max = input.max();
+            let log_sum = input.iter().map(|n| (n - max).exp()).sum::<f64>().ln() + max;
+            input.map(|n| n - log_sum)
         }
     }
 
+    fn sigmoid(n: f64) -> f64 {
+        1.0 / (1.0 + (-n).exp())
+    }
+
     #[derive(Clone, Copy)]
     pub struct Cost {
+        pub name: &'static str,
         pub fun: fn(&Vector, &Vector) -> f64,
         pub deriv: fn(&Vector, &Vector) -> Vector,
     }
@@ -224,13 +1286,20 @@ mod funcs {
     #[allow(dead_code)]
     impl Cost {
         pub const SQUARE: Self = Self {
+            name: "square",
             fun: Self::square,
             deriv: Self::square_deriv,
         };
         pub const CAT_CE: Self = Self {
+            name: "cat_ce",
             fun: Self::cat_ce,
             deriv: Self::cat_ce_deriv,
         };
+        const ALL: &'static [Self] = &[Self::SQUARE, Self::CAT_CE];
+
+        pub fn from_name(name: &str) -> Option<Self> {
+            Self::ALL.iter().copied().find(|cost| cost.name == name)
+        }
 
         fn square(actual: &Vector, expected: &Vector) -> f64 {
             let mut error = actual - expected;
@@ -244,12 +1313,152 @@ mod funcs {
         }
 
         fn cat_ce(actual: &Vector, expected: &Vector) -> f64 {
-            let mut cost = actual.clone().apply_into(|n| *n = n.ln());
+            let mut cost = actual
+                .clone()
+                .apply_into(|n| *n = n.max(f64::MIN_POSITIVE).ln());
             cost.component_mul_assign(expected);
             -cost.sum()
         }
         fn cat_ce_deriv(actual: &Vector, expected: &Vector) -> Vector {
-            -expected.component_div(actual)
+            -expected.component_div(&actual.map(|n| n.max(f64::MIN_POSITIVE)))
+        }
+
+        /// Cross-entropy of `softmax(logits)`, computed via log-softmax so
+        /// that it stays finite when a probability underflows to zero.
+        pub fn softmax_cat_ce(logits: &Vector, expected: &Vector) -> f64 {
+            -Activation::log_softmax(logits).dot(expected)
+        }
+        /// Derivative of [`Self::softmax_cat_ce`] with respect to the logits,
+        /// given `actual = softmax(logits)`.
+        pub fn softmax_cat_ce_deriv(actual: &Vector, expected: &Vector) -> Vector {
+            actual * expected.sum() - expected
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    /// A network with every kind of layer that has a batched code path,
+    /// except dropout, whose masks change between passes.
+    fn network(rng: &mut impl Rng) -> Network {
+        let spec = |kind, activation: &str| {
+            let activation = Activation::from_name(activation).unwrap();
+            (LayerSpec { kind, activation }, Init::XavierNormal)
+        };
+        let layers = [
+            spec(
+                LayerKind::Conv {
+                    channels: 2,
+                    kernel: 3,
+                    stride: 1,
+                    padding: 1,
+                },
+                "tanh",
+            ),
+            spec(LayerKind::MaxPool { size: 2, stride: 2 }, "identity"),
+            spec(LayerKind::AvgPool { size: 2, stride: 1 }, "identity"),
+            spec(LayerKind::Flatten, "identity"),
+            spec(LayerKind::Dense { size: 6 }, "identity"),
+            spec(LayerKind::BatchNorm, "tanh"),
+            spec(LayerKind::Dense { size: 5 }, "identity"),
+            spec(LayerKind::LayerNorm, "elu"),
+            spec(LayerKind::Dense { size: 3 }, "softmax"),
+        ];
+        let input = Shape {
+            channels: 1,
+            height: 8,
+            width: 8,
+        };
+        let nn = Network::new(input, &layers, rng).unwrap();
+        // Moves the normalization scales and shifts away from 1 and 0.
+        let params = nn
+            .conf
+            .read()
+            .unwrap()
+            .flatten()
+            .map(|n| n + rng.gen_range(-0.3..0.3));
+        nn.conf.write().unwrap().load_iter(params.iter().copied());
+        nn
+    }
+
+    #[test]
+    fn batch_gradient_matches_finite_differences() {
+        const STEP: f64 = 1.0e-5;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut nn = network(&mut rng);
+        let input = Matrix::from_fn(64, 7, |_, _| rng.gen_range(0.0..1.0));
+        let expected = Matrix::from_fn(3, 7, |r, c| (c % 3 == r) as u64 as f64);
+        nn.training = true;
+
+        for norm_group in [None, Some(3), Some(1)] {
+            nn.norm_group = norm_group;
+            nn.process_batch(&input);
+            let gradient = nn.batch_gradient(&expected);
+            let mut params = nn.conf.read().unwrap().flatten();
+            assert_eq!(gradient.len(), params.len());
+            let mut cost = |params: &Vector| {
+                nn.conf.write().unwrap().load_iter(params.iter().copied());
+                nn.process_batch(&input);
+                nn.batch_cost(&expected)
+            };
+            for i in 0..params.len() {
+                params[i] += STEP;
+                let upper = cost(&params);
+                params[i] -= 2.0 * STEP;
+                let lower = cost(&params);
+                params[i] += STEP;
+                let estimate = (upper - lower) / (2.0 * STEP);
+                assert!(
+                    (estimate - gradient[i]).abs() < 1.0e-6 * (1.0 + estimate.abs()),
+                    "parameter {i} with group {norm_group:?}: \
+                     backpropagation gave {}, finite differences {estimate}",
+                    gradient[i]
+                );
+            }
+            cost(&params);
+        }
+    }
+
+    #[test]
+    fn running_stats_cover_whole_batches() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut nn = network(&mut rng);
+        let input = Matrix::from_fn(64, 7, |_, _| rng.gen_range(0.0..1.0));
+        nn.training = true;
+        nn.norm_group = Some(2);
+
+        // The sums of two chunks of a batch, as workers return them.
+        nn.process_batch(&input.columns(0, 4).into_owned());
+        let mut sums = nn.batch_stats();
+        nn.process_batch(&input.columns(4, 3).into_owned());
+        sums += nn.batch_stats();
+
+        nn.process_batch(&input);
+        let index = nn
+            .conf
+            .read()
+            .unwrap()
+            .layers
+            .iter()
+            .position(|layer| layer.kind == LayerKind::BatchNorm)
+            .unwrap();
+        let batch_input = &nn.state.batch[index];
+        let (mean, var) = (batch_input.column_mean(), batch_input.column_variance());
+
+        let mut conf = nn.conf.read().unwrap().clone();
+        conf.load_running_stats(std::iter::repeat(0.0));
+        conf.update_running_stats(&sums, input.ncols());
+        let expected = (mean.iter().chain(var.iter()))
+            .map(|n| n * NORM_MOMENTUM)
+            .collect::<Vec<_>>();
+        let actual = conf.running_stats();
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!((actual - expected).abs() < 1.0e-12);
         }
     }
 }
@@ -0,0 +1,379 @@
+use crate::config::{Clipping, OptimizerConfig, Regularization};
+use crate::network::{NetConf, Vector};
+
+pub trait Optimizer {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64);
+
+    fn state(&self) -> State;
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()>;
+
+    fn update(&mut self, conf: &mut NetConf, gradient: &Vector, learning_rate: f64) {
+        let mut params = conf.flatten();
+        self.step(&mut params, gradient, learning_rate);
+        conf.load_iter(params.iter().copied());
+    }
+}
+
+/// Everything an optimizer needs to carry on from where it stopped.
+#[derive(Clone, Default)]
+pub struct State {
+    pub steps: u64,
+    pub buffers: Vec<Vector>,
+}
+
+impl State {
+    fn take<const N: usize>(self, param_count: usize) -> anyhow::Result<(u64, [Vector; N])> {
+        let count = self.buffers.len();
+        let buffers: [Vector; N] = self
+            .buffers
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("optimizer state has {count} buffers (expected {N})"))?;
+        if let Some(buffer) = buffers.iter().find(|b| b.nrows() != param_count) {
+            anyhow::bail!(
+                "optimizer state has {} parameters (expected {param_count})",
+                buffer.nrows()
+            );
+        }
+        Ok((self.steps, buffers))
+    }
+}
+
+impl OptimizerConfig {
+    /// Checks that the optimizer's hyperparameters are within range.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = |n: f64| n > 0.0;
+        let (name, decays, epsilon) = match *self {
+            Self::Sgd => return Ok(()),
+            Self::Nesterov { momentum_decay } => {
+                ("Nesterov", vec![("momentum_decay", momentum_decay)], None)
+            }
+            Self::Adam {
+                beta1,
+                beta2,
+                epsilon,
+                ..
+            } => (
+                "Adam",
+                vec![("beta1", beta1), ("beta2", beta2)],
+                Some(epsilon),
+            ),
+            Self::AdamW {
+                beta1,
+                beta2,
+                epsilon,
+                ..
+            } => (
+                "AdamW",
+                vec![("beta1", beta1), ("beta2", beta2)],
+                Some(epsilon),
+            ),
+            Self::RmsProp { decay, epsilon, .. } => {
+                ("RmsProp", vec![("decay", decay)], Some(epsilon))
+            }
+            Self::Adagrad { epsilon, .. } => ("Adagrad", vec![], Some(epsilon)),
+        };
+        if let Some((field, _)) = decays.iter().find(|(_, n)| !(0.0..1.0).contains(n)) {
+            anyhow::bail!("`{field}` of the `{name}` optimizer must be at least 0 and less than 1");
+        }
+        if epsilon.is_some_and(|epsilon| !positive(epsilon)) {
+            anyhow::bail!("`epsilon` of the `{name}` optimizer must be positive");
+        }
+        let weight_decay = match *self {
+            Self::Adam { weight_decay, .. }
+            | Self::AdamW { weight_decay, .. }
+            | Self::RmsProp { weight_decay, .. }
+            | Self::Adagrad { weight_decay, .. } => weight_decay,
+            Self::Sgd | Self::Nesterov { .. } => 0.0,
+        };
+        if !(0.0..).contains(&weight_decay) {
+            anyhow::bail!("`weight_decay` of the `{name}` optimizer must not be negative");
+        }
+        Ok(())
+    }
+
+    pub fn build(&self, conf: &NetConf) -> Box<dyn Optimizer> {
+        let zeros = || Vector::from_element(conf.param_count(), 0.0);
+        let adam = |beta1, beta2, epsilon| Adam {
+            beta1,
+            beta2,
+            epsilon,
+            m: zeros(),
+            v: zeros(),
+            t: 0,
+        };
+        let (optimizer, weight_decay): (Box<dyn Optimizer>, _) = match *self {
+            Self::Sgd => (Box::new(Sgd), 0.0),
+            Self::Nesterov { momentum_decay } => (
+                Box::new(Nesterov {
+                    momentum_decay,
+                    momentum: zeros(),
+                }),
+                0.0,
+            ),
+            Self::Adam {
+                beta1,
+                beta2,
+                epsilon,
+                weight_decay,
+            }
+            | Self::AdamW {
+                beta1,
+                beta2,
+                epsilon,
+                weight_decay,
+            } => (Box::new(adam(beta1, beta2, epsilon)), weight_decay),
+            Self::RmsProp {
+                decay,
+                epsilon,
+                weight_decay,
+            } => (
+                Box::new(RmsProp {
+                    decay,
+                    epsilon,
+                    v: zeros(),
+                }),
+                weight_decay,
+            ),
+            Self::Adagrad {
+                epsilon,
+                weight_decay,
+            } => (
+                Box::new(Adagrad {
+                    epsilon,
+                    v: zeros(),
+                }),
+                weight_decay,
+            ),
+        };
+        if weight_decay == 0.0 {
+            return optimizer;
+        }
+        let decay_mask = conf.weight_mask();
+        if let Self::Adam { .. } = self {
+            return Box::new(Coupled {
+                optimizer,
+                weight_decay,
+                decay_mask,
+            });
+        }
+        Box::new(Decoupled {
+            optimizer,
+            weight_decay,
+            decay_mask,
+        })
+    }
+}
+
+impl Regularization {
+    /// L1 and L2 penalty on the weights of `conf`, to be added to the cost.
+    pub fn penalty(&self, conf: &NetConf) -> f64 {
+        conf.flatten()
+            .component_mul(&conf.weight_mask())
+            .iter()
+            .map(|w| self.l1 * w.abs() + 0.5 * self.l2 * w * w)
+            .sum()
+    }
+
+    /// Gradient of [`Self::penalty`] with respect to the parameters.
+    pub fn gradient(&self, conf: &NetConf) -> Vector {
+        conf.flatten()
+            .component_mul(&conf.weight_mask())
+            .map(|w| self.l1 * w.signum() * (w != 0.0) as u64 as f64 + self.l2 * w)
+    }
+}
+
+impl Clipping {
+    pub fn apply(&self, gradient: &mut Vector) {
+        if let Some(max) = self.value {
+            gradient.apply(|n| *n = n.clamp(-max, max));
+        }
+        if let Some(max) = self.norm {
+            let norm = gradient.norm();
+            if norm > max {
+                *gradient *= max / norm;
+            }
+        }
+    }
+}
+
+pub struct Sgd;
+
+impl Optimizer for Sgd {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        params.axpy(-learning_rate, gradient, 1.0);
+    }
+
+    fn state(&self) -> State {
+        State::default()
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        state.take::<0>(0).map(drop)
+    }
+}
+
+/// Momentum with Nesterov accelerated gradient.
+pub struct Nesterov {
+    momentum_decay: f64,
+    momentum: Vector,
+}
+
+impl Optimizer for Nesterov {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        let scaled_gradient = learning_rate * gradient;
+        self.momentum = self.momentum_decay * &self.momentum + &scaled_gradient;
+        let step = self.momentum_decay * &self.momentum + scaled_gradient;
+        *params -= step;
+    }
+
+    fn state(&self) -> State {
+        State {
+            steps: 0,
+            buffers: vec![self.momentum.clone()],
+        }
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        [self.momentum] = state.take(self.momentum.nrows())?.1;
+        Ok(())
+    }
+}
+
+/// Weight decay added to the gradient (an L2 penalty) before another
+/// optimizer's step, as in Adam, and only for parameters selected by
+/// `decay_mask`.
+pub struct Coupled {
+    optimizer: Box<dyn Optimizer>,
+    weight_decay: f64,
+    decay_mask: Vector,
+}
+
+impl Optimizer for Coupled {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        let gradient = gradient + params.component_mul(&self.decay_mask) * self.weight_decay;
+        self.optimizer.step(params, &gradient, learning_rate);
+    }
+
+    fn state(&self) -> State {
+        self.optimizer.state()
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        self.optimizer.load_state(state)
+    }
+}
+
+/// Weight decay decoupled from the gradient, applied after another
+/// optimizer's step (as in AdamW) and only to parameters selected by
+/// `decay_mask`.
+pub struct Decoupled {
+    optimizer: Box<dyn Optimizer>,
+    weight_decay: f64,
+    decay_mask: Vector,
+}
+
+impl Optimizer for Decoupled {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        let decay = params.component_mul(&self.decay_mask) * (learning_rate * self.weight_decay);
+        self.optimizer.step(params, gradient, learning_rate);
+        *params -= decay;
+    }
+
+    fn state(&self) -> State {
+        self.optimizer.state()
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        self.optimizer.load_state(state)
+    }
+}
+
+pub struct Adam {
+    beta1: f64,
+    beta2: f64,
+    epsilon: f64,
+    m: Vector,
+    v: Vector,
+    t: u64,
+}
+
+impl Optimizer for Adam {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        self.t += 1;
+        self.m = self.beta1 * &self.m + (1.0 - self.beta1) * gradient;
+        self.v = self.beta2 * &self.v + (1.0 - self.beta2) * gradient.component_mul(gradient);
+        let m_corr = 1.0 - self.beta1.powf(self.t as f64);
+        let v_corr = 1.0 - self.beta2.powf(self.t as f64);
+        for (i, param) in params.iter_mut().enumerate() {
+            let m_hat = self.m[i] / m_corr;
+            let v_hat = self.v[i] / v_corr;
+            *param -= learning_rate * m_hat / (v_hat.sqrt() + self.epsilon);
+        }
+    }
+
+    fn state(&self) -> State {
+        State {
+            steps: self.t,
+            buffers: vec![self.m.clone(), self.v.clone()],
+        }
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        (self.t, [self.m, self.v]) = state.take(self.m.nrows())?;
+        Ok(())
+    }
+}
+
+pub struct RmsProp {
+    decay: f64,
+    epsilon: f64,
+    v: Vector,
+}
+
+impl Optimizer for RmsProp {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        self.v = self.decay * &self.v + (1.0 - self.decay) * gradient.component_mul(gradient);
+        for (i, param) in params.iter_mut().enumerate() {
+            *param -= learning_rate * gradient[i] / (self.v[i].sqrt() + self.epsilon);
+        }
+    }
+
+    fn state(&self) -> State {
+        State {
+            steps: 0,
+            buffers: vec![self.v.clone()],
+        }
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        [self.v] = state.take(self.v.nrows())?.1;
+        Ok(())
+    }
+}
+
+pub struct Adagrad {
+    epsilon: f64,
+    v: Vector,
+}
+
+impl Optimizer for Adagrad {
+    fn step(&mut self, params: &mut Vector, gradient: &Vector, learning_rate: f64) {
+        self.v += gradient.component_mul(gradient);
+        for (i, param) in params.iter_mut().enumerate() {
+            *param -= learning_rate * gradient[i] / (self.v[i].sqrt() + self.epsilon);
+        }
+    }
+
+    fn state(&self) -> State {
+        State {
+            steps: 0,
+            buffers: vec![self.v.clone()],
+        }
+    }
+
+    fn load_state(&mut self, state: State) -> anyhow::Result<()> {
+        [self.v] = state.take(self.v.nrows())?.1;
+        Ok(())
+    }
+}
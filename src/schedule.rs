@@ -0,0 +1,91 @@
+use crate::config::Schedule;
+use std::f64::consts::PI;
+
+impl Schedule {
+    /// Checks that the schedule's parameters are within range.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = |n: f64| n > 0.0;
+        match *self {
+            Self::Step { step_size: 0, .. } => {
+                anyhow::bail!("`step_size` of the `Step` schedule must be positive")
+            }
+            Self::Step { gamma, .. } if !positive(gamma) => {
+                anyhow::bail!("`gamma` of the `Step` schedule must be positive")
+            }
+            Self::Exponential { gamma } if !positive(gamma) => {
+                anyhow::bail!("`gamma` of the `Exponential` schedule must be positive")
+            }
+            Self::Cosine { period, .. } if !positive(period) => {
+                anyhow::bail!("`period` of the `Cosine` schedule must be positive")
+            }
+            Self::Cosine { period_mult, .. } if !(1.0..).contains(&period_mult) => {
+                anyhow::bail!("`period_mult` of the `Cosine` schedule must be at least 1")
+            }
+            Self::Cosine { min_rate, .. } if !(0.0..).contains(&min_rate) => {
+                anyhow::bail!("`min_rate` of the `Cosine` schedule must not be negative")
+            }
+            Self::OneCycle { pct_start, .. } if !(0.0..1.0).contains(&pct_start) => {
+                anyhow::bail!(
+                    "`pct_start` of the `OneCycle` schedule must be at least 0 and less than 1"
+                )
+            }
+            Self::OneCycle { div, .. } if !positive(div) => {
+                anyhow::bail!("`div` of the `OneCycle` schedule must be positive")
+            }
+            Self::OneCycle { final_div, .. } if !positive(final_div) => {
+                anyhow::bail!("`final_div` of the `OneCycle` schedule must be positive")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Learning rate after `progress` epochs (fractional, advanced per
+    /// batch) out of `epochs` total, for a base rate of `base`.
+    pub fn rate(&self, base: f64, progress: f64, epochs: f64) -> f64 {
+        match *self {
+            Self::Constant => base,
+            Self::Step { step_size, gamma } => {
+                base * gamma.powi((progress / step_size as f64).floor() as i32)
+            }
+            Self::Exponential { gamma } => base * gamma.powf(progress),
+            Self::Cosine {
+                period,
+                min_rate,
+                period_mult,
+            } => {
+                let (mut t, mut period) = (progress, period);
+                while t >= period {
+                    t -= period;
+                    period *= period_mult;
+                }
+                anneal(base, min_rate, t / period)
+            }
+            Self::OneCycle {
+                pct_start,
+                div,
+                final_div,
+            } => {
+                let peak = epochs * pct_start;
+                if progress < peak {
+                    anneal(base / div, base, progress / peak)
+                } else {
+                    anneal(base, base / final_div, (progress - peak) / (epochs - peak))
+                }
+            }
+        }
+    }
+}
+
+/// Linear warmup factor for the first `warmup` epochs.
+pub fn warmup(progress: f64, warmup: f64) -> f64 {
+    if progress < warmup {
+        progress / warmup
+    } else {
+        1.0
+    }
+}
+
+/// Cosine interpolation from `from` (at `t = 0`) to `to` (at `t = 1`).
+fn anneal(from: f64, to: f64, t: f64) -> f64 {
+    to + (from - to) * (1.0 + (PI * t.clamp(0.0, 1.0)).cos()) / 2.0
+}
@@ -1,15 +1,19 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
 use std::thread;
 
 pub struct ThreadPool<T: Send + 'static, S: Send + 'static> {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message<T, S>>,
-    receiver: mpsc::Receiver<T>,
+    receiver: mpsc::Receiver<(usize, anyhow::Result<T>)>,
+    /// Number of jobs executed so far, used to order their results.
+    submitted: Cell<usize>,
 }
 
-type Job<T, S> = Box<dyn FnOnce(&mut S) -> Option<T> + Send + 'static>;
+type Job<T, S> = Box<dyn FnOnce(&mut S) -> anyhow::Result<T> + Send + 'static>;
 enum Message<T: Send + 'static, S: Send + 'static> {
-    Job(Job<T, S>),
+    Job(usize, Job<T, S>),
     Terminate,
 }
 
@@ -28,28 +32,48 @@ impl<T: Send + 'static, S: Send + 'static> ThreadPool<T, S> {
             workers,
             sender: m_sender,
             receiver: res_receiver,
+            submitted: Cell::new(0),
         }
     }
 
-    pub fn execute<F: FnOnce(&mut S) -> Option<T> + Send + 'static>(&self, f: F) {
+    /// Runs `f` on a worker. If it panics, the panic becomes its result, and
+    /// the worker carries on with its state as the job left it.
+    pub fn execute<F: FnOnce(&mut S) -> anyhow::Result<T> + Send + 'static>(&self, f: F) {
         let job = Box::new(f);
-        self.sender.send(Message::Job(job)).unwrap();
+        let index = self.submitted.replace(self.submitted.get() + 1);
+        // If every worker has stopped, `results` reports it.
+        let _ = self.sender.send(Message::Job(index, job));
     }
 
-    pub fn results(&self, count: usize) -> impl Iterator<Item = T> + '_ {
-        (0..count).map(|_| self.receiver.recv().unwrap())
+    /// Waits for `count` results, and returns them in the order their jobs
+    /// were executed regardless of which finished first, or the first job's
+    /// error if any failed.
+    pub fn results(&self, count: usize) -> anyhow::Result<impl Iterator<Item = T>> {
+        let mut results = (0..count)
+            .map(|_| {
+                self.receiver
+                    .recv()
+                    .map_err(|_| anyhow::anyhow!("all worker threads have stopped"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        results.sort_unstable_by_key(|(index, _)| *index);
+        let results = results
+            .into_iter()
+            .map(|(_, res)| res)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(results.into_iter())
     }
 }
 
 impl<T: Send + 'static, S: Send + 'static> Drop for ThreadPool<T, S> {
     fn drop(&mut self) {
         for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+            let _ = self.sender.send(Message::Terminate);
         }
 
         for worker in &mut self.workers {
             if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+                let _ = thread.join();
             }
         }
     }
@@ -62,21 +86,33 @@ struct Worker {
 impl Worker {
     fn new<T: Send + 'static, S: Send + 'static>(
         receiver: Arc<Mutex<mpsc::Receiver<Message<T, S>>>>,
-        sender: mpsc::Sender<T>,
+        sender: mpsc::Sender<(usize, anyhow::Result<T>)>,
         state: S,
     ) -> Self {
         let thread = thread::spawn(move || {
             let mut state = state;
             loop {
-                let message = receiver.lock().unwrap().recv().unwrap();
+                // The lock is only held while waiting for a message, never
+                // while running a job.
+                let message = receiver
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .recv();
 
                 match message {
-                    Message::Job(job) => {
-                        if let Some(res) = job(&mut state) {
-                            sender.send(res).unwrap();
+                    Ok(Message::Job(index, job)) => {
+                        let res = panic::catch_unwind(AssertUnwindSafe(|| job(&mut state)))
+                            .unwrap_or_else(|payload| {
+                                Err(anyhow::anyhow!(
+                                    "worker panicked: {}",
+                                    panic_message(&*payload)
+                                ))
+                            });
+                        if sender.send((index, res)).is_err() {
+                            break;
                         }
                     }
-                    Message::Terminate => break,
+                    Ok(Message::Terminate) | Err(_) => break,
                 }
             }
         });
@@ -86,3 +122,59 @@ impl Worker {
         }
     }
 }
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(result: anyhow::Result<impl Iterator<Item = usize>>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn survives_a_panicking_job() {
+        let pool = ThreadPool::<usize, ()>::new(2, || ());
+        pool.execute(|_| Ok(1));
+        pool.execute(|_| panic!("oops"));
+        let err = error(pool.results(2));
+        assert!(err.contains("worker panicked: oops"), "{err}");
+
+        (0..4).for_each(|i| pool.execute(move |_| Ok(i)));
+        assert_eq!(pool.results(4).unwrap().collect::<Vec<_>>(), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn surfaces_job_errors() {
+        let pool = ThreadPool::<usize, ()>::new(2, || ());
+        pool.execute(|_| Ok(1));
+        pool.execute(|_| anyhow::bail!("bad sample"));
+        assert_eq!(error(pool.results(2)), "bad sample");
+    }
+
+    #[test]
+    fn orders_results_by_submission() {
+        let pool = ThreadPool::<usize, ()>::new(2, || ());
+        // The first job only finishes once the second has.
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move |_| {
+            receiver.recv().unwrap();
+            Ok(0)
+        });
+        pool.execute(move |_| {
+            sender.send(()).unwrap();
+            Ok(1)
+        });
+        assert_eq!(pool.results(2).unwrap().collect::<Vec<_>>(), [0, 1]);
+    }
+}